@@ -13,29 +13,27 @@
 //! do_something(result.stdout_utf8());
 //! # Ok(()) }
 //! ```
+//!
+//! Cache decisions (hits, misses, expirations, cleanups, and so on) are reported through the
+//! [`log`](https://docs.rs/log) facade, so applications can route them into their own logging by
+//! installing a logger of their choice.
 #![warn(missing_docs)]
 
+use std::borrow::Cow;
 use std::collections::BTreeMap;
 use std::ffi::{OsString, OsStr};
 use std::fs::{File, OpenOptions};
 use std::hash::{Hash, Hasher};
 use std::io::{self, BufReader, ErrorKind, BufWriter, Write};
 use std::path::{PathBuf, Path};
-use std::process::{Command};
+use std::process::{Command, Stdio};
 use std::time::{Duration, Instant, SystemTime};
 
 use anyhow::{Context, Error, Result};
+use log::{debug, trace, warn};
 use serde::{Serialize, Deserialize};
 use serde::de::DeserializeOwned;
 
-#[cfg(feature="debug")]
-macro_rules! debug_msg {
-    ($($arg:tt)*) => { eprintln!("bkt: {}", format!($($arg)*)) }
-}
-#[cfg(not(feature="debug"))]
-macro_rules! debug_msg {
-    ($($arg:tt)*) => {  }
-}
 
 /// Describes a command to be executed and cached. This struct also serves as the cache key.
 /// It consists of a command line invocation and, optionally, a working directory to execute in and
@@ -52,6 +50,242 @@ pub struct CommandDesc {
     args: Vec<OsString>,
     cwd: Option<PathBuf>,
     env: BTreeMap<OsString, OsString>,
+    executable: Option<ExecutableIdentity>,
+    git_state: Option<GitState>,
+    // The start of the current period of an expiry Schedule, see CommandDesc::with_expiry()
+    period_start: Option<SystemTime>,
+    isolated_env: bool,
+    pty: bool,
+    // Salted digests of secret_values, populated by Bkt::seal_secrets()
+    secret_env: BTreeMap<OsString, String>,
+    #[serde(skip)]
+    secret_values: SecretValues,
+    #[serde(skip)]
+    run_dir: RunDir,
+}
+
+/// Environment variables that are passed to the subprocess but must not be persisted. These are
+/// excluded from serialization, equality, hashing, and debug output; only their salted digests in
+/// [`CommandDesc::secret_env`] contribute to the cache key.
+#[derive(Clone, Default)]
+struct SecretValues(BTreeMap<OsString, OsString>);
+
+impl PartialEq for SecretValues {
+    fn eq(&self, _: &Self) -> bool { true }
+}
+
+impl Eq for SecretValues {}
+
+impl Hash for SecretValues {
+    fn hash<H: Hasher>(&self, _: &mut H) {}
+}
+
+impl std::fmt::Debug for SecretValues {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_map().entries(self.0.keys().map(|k| (k, "<redacted>"))).finish()
+    }
+}
+
+/// The directory to run a command without a working directory in, instead of the current
+/// process', such as where [`Bkt::watch()`] refreshes a command registered from. Unlike
+/// [`CommandDesc::cwd`] this is excluded from serialization, equality, and hashing, so it doesn't
+/// change the cache key.
+#[derive(Clone, Debug, Default)]
+struct RunDir(Option<PathBuf>);
+
+impl PartialEq for RunDir {
+    fn eq(&self, _: &Self) -> bool { true }
+}
+
+impl Eq for RunDir {}
+
+impl Hash for RunDir {
+    fn hash<H: Hasher>(&self, _: &mut H) {}
+}
+
+/// Environment variables that are passed through to commands run with
+/// [`CommandDesc::with_isolated_env()`].
+pub const ISOLATED_ENV_ALLOWLIST: &[&str] = &["PATH", "HOME"];
+
+/// Identifies a specific version of an executable file, so that replacing or upgrading the
+/// executable changes the cache key.
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+struct ExecutableIdentity {
+    path: PathBuf,
+    modified: SystemTime,
+    len: u64,
+    inode: u64,
+}
+
+impl ExecutableIdentity {
+    /// Resolves the given program name the way the OS would when spawning it: names containing a
+    /// path separator are resolved relative to the working directory, others are searched for
+    /// on the `PATH`.
+    fn resolve(program: &OsStr, cwd: Option<&Path>) -> Result<Self> {
+        let program_path = Path::new(program);
+        let path = if program_path.components().count() > 1 {
+            match cwd {
+                Some(cwd) => cwd.join(program_path),
+                None => program_path.into(),
+            }
+        } else {
+            std::env::var_os("PATH").iter()
+                .flat_map(std::env::split_paths)
+                .flat_map(|dir| ExecutableIdentity::candidates(&dir, program))
+                .find(|p| ExecutableIdentity::is_executable(p))
+                .ok_or_else(|| Error::msg(format!("{} not found on PATH", program.to_string_lossy())))?
+        };
+        let path = path.canonicalize()
+            .with_context(|| format!("Failed to resolve executable {}", path.display()))?;
+        let metadata = std::fs::metadata(&path)?;
+        #[cfg(unix)]
+        let inode = std::os::unix::fs::MetadataExt::ino(&metadata);
+        #[cfg(not(unix))]
+        let inode = 0;
+        Ok(ExecutableIdentity { modified: metadata.modified()?, len: metadata.len(), inode, path })
+    }
+
+    #[cfg(not(windows))]
+    fn candidates(dir: &Path, program: &OsStr) -> Vec<PathBuf> {
+        vec![dir.join(program)]
+    }
+
+    #[cfg(windows)]
+    fn candidates(dir: &Path, program: &OsStr) -> Vec<PathBuf> {
+        vec![dir.join(program), dir.join(program).with_extension("exe")]
+    }
+
+    #[cfg(unix)]
+    fn is_executable(path: &Path) -> bool {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::metadata(path).map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0).unwrap_or(false)
+    }
+
+    #[cfg(not(unix))]
+    fn is_executable(path: &Path) -> bool {
+        path.is_file()
+    }
+}
+
+/// Identifies the state of a git repository, so that commits, checkouts, and staging changes (and
+/// optionally edits to the working tree) change the cache key.
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+struct GitState {
+    git_dir: PathBuf,
+    head: String,
+    index_modified: Option<SystemTime>,
+    dirty: Option<String>,
+}
+
+impl GitState {
+    /// Inspects the repository containing `dir`. Only computing the `dirty` fingerprint requires
+    /// running git.
+    fn resolve(dir: &Path, include_dirty: bool) -> Result<Self> {
+        let (work_tree, git_dir) = GitState::find_repo(dir)?;
+        let head = GitState::read_head(&work_tree, &git_dir)?;
+        // Fingerprint first, in case git status still refreshes the index despite being asked not to
+        let dirty = if include_dirty { Some(GitState::dirty_fingerprint(&work_tree)?) } else { None };
+        let index_modified = match std::fs::metadata(git_dir.join("index")) {
+            Ok(metadata) => Some(metadata.modified()?),
+            // A new repository has no index yet
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => return Err(Error::new(e).context("Failed to read git index")),
+        };
+        Ok(GitState { git_dir, head, index_modified, dirty })
+    }
+
+    /// Finds the repository containing `dir`, returning its working tree and git directory.
+    fn find_repo(dir: &Path) -> Result<(PathBuf, PathBuf)> {
+        let dir = dir.canonicalize().with_context(|| format!("Failed to resolve {}", dir.display()))?;
+        for candidate in dir.ancestors() {
+            let dot_git = candidate.join(".git");
+            if dot_git.is_dir() {
+                return Ok((candidate.into(), dot_git));
+            }
+            // Worktrees and submodules have a .git file pointing to their git directory
+            if dot_git.is_file() {
+                let contents = std::fs::read_to_string(&dot_git)?;
+                let git_dir = contents.trim().strip_prefix("gitdir:")
+                    .ok_or_else(|| Error::msg(format!("Unexpected contents in {}", dot_git.display())))?;
+                return Ok((candidate.into(), candidate.join(git_dir.trim())));
+            }
+        }
+        Err(Error::msg(format!("{} is not in a git repository", dir.display())))
+    }
+
+    /// Resolves HEAD to a commit hash by reading the repository's refs directly, only falling
+    /// back to running git if they're stored in a format this doesn't understand.
+    fn read_head(work_tree: &Path, git_dir: &Path) -> Result<String> {
+        let head = std::fs::read_to_string(git_dir.join("HEAD")).context("Failed to read git HEAD")?;
+        let head = head.trim();
+        let reference = match head.strip_prefix("ref:") {
+            Some(reference) => reference.trim(),
+            None => return Ok(head.into()),
+        };
+        // Worktrees share most refs with the main repository
+        let common_dir = match std::fs::read_to_string(git_dir.join("commondir")) {
+            Ok(common_dir) => git_dir.join(common_dir.trim()),
+            Err(_) => git_dir.into(),
+        };
+        for dir in [git_dir, &common_dir] {
+            if let Ok(commit) = std::fs::read_to_string(dir.join(reference)) {
+                return Ok(commit.trim().into());
+            }
+        }
+        if let Ok(packed) = std::fs::read_to_string(common_dir.join("packed-refs")) {
+            let commit = packed.lines()
+                .filter_map(|line| line.split_once(' '))
+                .find(|&(_, name)| name == reference);
+            if let Some((commit, _)) = commit {
+                return Ok(commit.into());
+            }
+        }
+        let output = Command::new("git").arg("-C").arg(work_tree).args(["rev-parse", "--verify", "-q", "HEAD"])
+            .stdin(Stdio::null()).stderr(Stdio::null()).output();
+        match output {
+            Ok(output) if output.status.success() => Ok(String::from_utf8_lossy(&output.stdout).trim().into()),
+            // An unborn branch, e.g. in a new repository
+            _ => Ok(head.into()),
+        }
+    }
+
+    /// Fingerprints uncommitted changes in the working tree, using `git status` to find changed
+    /// files and their sizes and modification times to detect further edits to them.
+    fn dirty_fingerprint(work_tree: &Path) -> Result<String> {
+        use sha2::{Digest, Sha256};
+        // By default git status rewrites the index to refresh its stat cache, which would change
+        // the index's modification time and therefore the cache key
+        let output = Command::new("git").arg("-C").arg(work_tree).args(["status", "--porcelain=v1", "-z"])
+            .env("GIT_OPTIONAL_LOCKS", "0")
+            .stdin(Stdio::null()).stderr(Stdio::null()).output()
+            .context("Failed to run git status")?;
+        if !output.status.success() {
+            return Err(Error::msg(format!("git status failed with {}", output.status)));
+        }
+        let mut hasher = Sha256::new();
+        hasher.update(&output.stdout);
+        let mut entries = output.stdout.split(|&b| b == 0);
+        while let Some(entry) = entries.next() {
+            if entry.len() < 4 {
+                continue;
+            }
+            // Renames and copies are followed by the original path, which no longer matters
+            if entry[0] == b'R' || entry[0] == b'C' {
+                entries.next();
+            }
+            #[cfg(unix)]
+            let path = work_tree.join(<OsStr as std::os::unix::ffi::OsStrExt>::from_bytes(&entry[3..]));
+            #[cfg(not(unix))]
+            let path = work_tree.join(String::from_utf8_lossy(&entry[3..]).as_ref());
+            if let Ok(metadata) = std::fs::metadata(&path) {
+                hasher.update(metadata.len().to_le_bytes());
+                if let Ok(Ok(modified)) = metadata.modified().map(|m| m.duration_since(SystemTime::UNIX_EPOCH)) {
+                    hasher.update(modified.as_nanos().to_le_bytes());
+                }
+            }
+        }
+        Ok(hex(&hasher.finalize()))
+    }
 }
 
 impl CommandDesc {
@@ -65,6 +299,14 @@ impl CommandDesc {
             args: command.into_iter().map(Into::into).collect(),
             cwd: None,
             env: BTreeMap::new(),
+            executable: None,
+            git_state: None,
+            period_start: None,
+            isolated_env: false,
+            pty: false,
+            secret_env: BTreeMap::new(),
+            secret_values: SecretValues::default(),
+            run_dir: RunDir::default(),
         };
         assert!(!ret.args.is_empty(), "Command cannot be empty");
         ret
@@ -158,6 +400,256 @@ impl CommandDesc {
         }
         self
     }
+
+    /// Adds all variables in the current process' environment whose names match the given glob
+    /// pattern to the environment the command should be run from, and causes these pairs to be
+    /// included in the cache key. In the pattern `*` matches any sequence of characters and `?`
+    /// matches any single character; other characters match only themselves. Variables with
+    /// non-Unicode names never match.
+    ///
+    /// ```
+    /// let cmd = bkt::CommandDesc::new(["aws", "sts", "get-caller-identity"])
+    ///     .with_envs_matching("AWS_*");
+    /// ```
+    pub fn with_envs_matching(self, pattern: &str) -> Self {
+        self.with_envs_where(|k| k.to_str().map(|k| glob_matches(pattern, k)).unwrap_or(false))
+    }
+
+    /// Adds all variables in the current process' environment _except_ those whose names match
+    /// any of the given glob patterns (see [`with_envs_matching`](CommandDesc::with_envs_matching))
+    /// to the environment the command should be run from, and causes these pairs to be included in
+    /// the cache key.
+    ///
+    /// ```
+    /// let cmd = bkt::CommandDesc::new(["env"]).with_envs_excluding(["RANDOM", "SHLVL", "_"]);
+    /// ```
+    pub fn with_envs_excluding<I, S>(self, patterns: I) -> Self
+            where I: IntoIterator<Item=S>, S: AsRef<str> {
+        let patterns: Vec<_> = patterns.into_iter().collect();
+        self.with_envs_where(|k| match k.to_str() {
+            Some(k) => !patterns.iter().any(|p| glob_matches(p.as_ref(), k)),
+            None => true,
+        })
+    }
+
+    /// Adds all variables in the current process' environment whose names satisfy the given
+    /// predicate to the environment the command should be run from, and causes these pairs to be
+    /// included in the cache key. This is useful for selection logic, such as regular expressions,
+    /// that other methods don't support.
+    ///
+    /// ```
+    /// let cmd = bkt::CommandDesc::new(["kubectl", "get", "pods"])
+    ///     .with_envs_where(|k| k.to_string_lossy().starts_with("KUBE"));
+    /// ```
+    pub fn with_envs_where<F>(self, mut predicate: F) -> Self where F: FnMut(&OsStr) -> bool {
+        let envs: Vec<_> = std::env::vars_os().filter(|(k, _)| predicate(k)).collect();
+        self.with_envs(envs)
+    }
+
+    /// Adds the given key/value pair to the environment the command should be run from, treating
+    /// the value as a secret: rather than the value itself only a salted hash of it is included in
+    /// the cache key and persisted to the cache directory. The salt is randomly generated per
+    /// cache directory, so the same secret is hashed differently by different caches.
+    ///
+    /// ```
+    /// let cmd = bkt::CommandDesc::new(["curl", "https://example.com"])
+    ///     .with_secret_env_value("API_TOKEN", "hunter2");
+    /// ```
+    pub fn with_secret_env_value<K, V>(mut self, key: K, value: V) -> Self
+            where K: AsRef<OsStr>, V: AsRef<OsStr> {
+        self.env.remove(key.as_ref());
+        self.secret_values.0.insert(key.as_ref().into(), value.as_ref().into());
+        self
+    }
+
+    /// Looks up the given environment variable in the current process' environment and, if set,
+    /// adds it to the command's environment as a secret. See
+    /// [`with_secret_env_value`](CommandDesc::with_secret_env_value). This has no effect on the
+    /// subprocess that will be executed (assuming the current process' environment remains
+    /// unchanged).
+    ///
+    /// If the given variable name is not found in the current process' environment this call is a
+    /// no-op, and the cache key will remain unchanged.
+    ///
+    /// ```
+    /// let cmd = bkt::CommandDesc::new(["gh", "pr", "list"]).with_secret_env("GITHUB_TOKEN");
+    /// ```
+    pub fn with_secret_env<K>(self, key: K) -> Self where K: AsRef<OsStr> {
+        match std::env::var_os(&key) {
+            Some(val) => self.with_secret_env_value(&key, val),
+            None => self,
+        }
+    }
+
+    /// Runs the command in an isolated environment containing _only_ the variables included in
+    /// the cache key, rather than inheriting the current process' environment. This guarantees
+    /// the cached output cannot depend on variables the key doesn't capture, which could otherwise
+    /// cause one caller's environment to poison the cache for others.
+    ///
+    /// Since few commands work without them, the variables in [`ISOLATED_ENV_ALLOWLIST`] (such as
+    /// `PATH`) are looked up in the current process' environment and added to the command, and
+    /// therefore also to the cache key.
+    ///
+    /// ```
+    /// let cmd = bkt::CommandDesc::new(["env"]).with_isolated_env().with_env("LANG");
+    /// ```
+    pub fn with_isolated_env(mut self) -> Self {
+        self.isolated_env = true;
+        for key in ISOLATED_ENV_ALLOWLIST {
+            self = self.with_env(key);
+        }
+        self
+    }
+
+    /// Runs the command with its stdout attached to a pseudo-terminal rather than a pipe, so that
+    /// programs which only emit colors or other formatting when writing to a terminal (such as
+    /// `ls` or `git`) do so, and causes this to be included in the cache key. The pseudo-terminal
+    /// has the same size as the current process' terminal, if it has one. Stderr and stdin are
+    /// unaffected.
+    ///
+    /// This is only supported on Unix; elsewhere executing the command will fail.
+    ///
+    /// ```
+    /// let cmd = bkt::CommandDesc::new(["ls", "--color=auto"]).with_pty();
+    /// ```
+    pub fn with_pty(mut self) -> Self {
+        self.pty = true;
+        self
+    }
+
+    /// Resolves the program to be invoked (the first element of the command line) through the
+    /// `PATH`, and includes the resolved location of the executable, along with its modification
+    /// time, size, and (on Unix) inode in the cache key. This causes cached invocations to be
+    /// invalidated when the executable is upgraded or replaced, rather than continuing to serve
+    /// output from the old version until the TTL expires.
+    ///
+    /// Note this only tracks the executable itself, not any libraries or scripts it may load.
+    ///
+    /// # Errors
+    ///
+    /// If the program cannot be found or its metadata cannot be read.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> anyhow::Result<()> {
+    /// let cmd = bkt::CommandDesc::new(["bash", "-c", "echo Hello"]).with_executable_identity()?;
+    /// # Ok(()) }
+    /// ```
+    pub fn with_executable_identity(mut self) -> Result<Self> {
+        self.executable = Some(ExecutableIdentity::resolve(&self.args[0], self.cwd.as_deref())?);
+        Ok(self)
+    }
+
+    /// Includes the state of the git repository containing the command's working directory (or
+    /// the current directory, if unset) in the cache key: the commit `HEAD` points to and the
+    /// modification time of the index. This causes cached invocations to be invalidated by
+    /// commits, checkouts, and staging changes, without being as coarse as keying on the working
+    /// directory's contents. This reads the repository's files directly rather than running git.
+    ///
+    /// Unstaged edits to the working tree are _not_ detected, see
+    /// [`with_dirty_git_state`](CommandDesc::with_dirty_git_state).
+    ///
+    /// # Errors
+    ///
+    /// If the directory is not in a git repository or the repository cannot be read.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # fn main() -> anyhow::Result<()> {
+    /// let cmd = bkt::CommandDesc::new(["git", "log", "-n", "10"]).with_git_state()?;
+    /// # Ok(()) }
+    /// ```
+    pub fn with_git_state(self) -> Result<Self> {
+        self.with_resolved_git_state(false)
+    }
+
+    /// Like [`with_git_state`](CommandDesc::with_git_state), but also includes a fingerprint of
+    /// any uncommitted changes in the working tree, so that editing files also invalidates cached
+    /// invocations. This requires running `git status`, which can be slow in large repositories.
+    ///
+    /// # Errors
+    ///
+    /// If the directory is not in a git repository, or running `git status` fails.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # fn main() -> anyhow::Result<()> {
+    /// let cmd = bkt::CommandDesc::new(["cargo", "clippy"]).with_dirty_git_state()?;
+    /// # Ok(()) }
+    /// ```
+    pub fn with_dirty_git_state(self) -> Result<Self> {
+        self.with_resolved_git_state(true)
+    }
+
+    fn with_resolved_git_state(mut self, include_dirty: bool) -> Result<Self> {
+        let dir = match &self.cwd {
+            Some(cwd) => cwd.clone(),
+            None => std::env::current_dir()?,
+        };
+        self.git_state = Some(GitState::resolve(&dir, include_dirty)?);
+        Ok(self)
+    }
+
+    /// Includes the most recent time matching the given schedule in the cache key, so that cached
+    /// invocations expire at the schedule's next boundary (e.g. midnight) regardless of when they
+    /// were cached. Cached invocations still also expire after the TTL passed to
+    /// [`Bkt::retrieve()`]; [`Schedule::next()`] and [`Schedule::previous()`] can be used to pick
+    /// a TTL spanning the whole period.
+    ///
+    /// # Errors
+    ///
+    /// If the schedule's most recent boundary cannot be determined, see [`Schedule::previous()`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> anyhow::Result<()> {
+    /// let schedule: bkt::Schedule = "midnight".parse()?;
+    /// let cmd = bkt::CommandDesc::new(["./daily-report.sh"]).with_expiry(&schedule)?;
+    /// # Ok(()) }
+    /// ```
+    pub fn with_expiry(mut self, schedule: &Schedule) -> Result<Self> {
+        self.period_start = Some(schedule.previous(SystemTime::now())?);
+        Ok(self)
+    }
+
+    /// The command line this CommandDesc will invoke.
+    pub fn args(&self) -> &[OsString] { &self.args }
+}
+
+/// Formats the given bytes, such as a digest, as lowercase hexadecimal.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Matches a glob pattern supporting `*` (any sequence) and `?` (any character) against the full
+/// given string.
+fn glob_matches(pattern: &str, s: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let s: Vec<char> = s.chars().collect();
+    let (mut p, mut i) = (0, 0);
+    // Position of the last * seen, and the position in s it has been expanded to
+    let mut backtrack: Option<(usize, usize)> = None;
+    while i < s.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == s[i]) {
+            p += 1;
+            i += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, i));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            // Let the last * consume one more character and try again
+            backtrack = Some((star, matched + 1));
+            p = star + 1;
+            i = matched + 1;
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
 }
 
 impl CacheKey for CommandDesc {
@@ -171,16 +663,32 @@ impl CacheKey for CommandDesc {
     }
 }
 
+/// The key under which the output of a probe command validating a cached command is stored, see
+/// [`Bkt::refresh_if_changed()`].
+#[derive(Debug, Hash, PartialEq, Serialize, Deserialize)]
+struct ProbeKey {
+    command: CommandDesc,
+    probe: CommandDesc,
+}
+
+impl CacheKey for ProbeKey {}
+
 impl From<&CommandDesc> for std::process::Command {
     fn from(desc: &CommandDesc) -> Self {
         let mut command = Command::new(&desc.args[0]);
         command.args(&desc.args[1..]);
-        if let Some(cwd) = &desc.cwd {
+        if let Some(cwd) = desc.cwd.as_ref().or(desc.run_dir.0.as_ref()) {
             command.current_dir(cwd);
         }
+        if desc.isolated_env {
+            command.env_clear();
+        }
         if !desc.env.is_empty() {
             command.envs(&desc.env);
         }
+        if !desc.secret_values.0.is_empty() {
+            command.envs(&desc.secret_values.0);
+        }
         command
     }
 }
@@ -205,6 +713,8 @@ mod cmd_tests {
             CommandDesc::new(["foo"]).with_working_dir("/bar/baz"),
             CommandDesc::new(["foo"]).with_env_value("a", "b"),
             CommandDesc::new(["foo"]).with_working_dir("/bar").with_env_value("a", "b"),
+            CommandDesc::new(["bash"]).with_executable_identity().unwrap(),
+            CommandDesc::new(["foo"]).with_isolated_env(),
         ];
 
         // https://old.reddit.com/r/rust/comments/2koptu/best_way_to_visit_all_pairs_in_a_vec/clnhxr5/
@@ -216,17 +726,116 @@ mod cmd_tests {
             }
         }
     }
+
+    #[test]
+    fn globs() {
+        assert!(glob_matches("FOO", "FOO"));
+        assert!(!glob_matches("FOO", "FOOD"));
+        assert!(glob_matches("AWS_*", "AWS_PROFILE"));
+        assert!(glob_matches("AWS_*", "AWS_"));
+        assert!(!glob_matches("AWS_*", "XAWS_PROFILE"));
+        assert!(glob_matches("*_TOKEN", "GITHUB_TOKEN"));
+        assert!(glob_matches("A*B*C", "AxxBxxBxxC"));
+        assert!(!glob_matches("A*B*C", "AxxBxxBxxD"));
+        assert!(glob_matches("?OO", "FOO"));
+        assert!(!glob_matches("?OO", "OO"));
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("**", "abc"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn executable_identity() {
+        use std::os::unix::fs::PermissionsExt;
+        use test_dir::{TestDir, DirBuilder, FileType};
+        let dir = TestDir::temp().create("bin/tool", FileType::ZeroFile(10));
+        let tool = dir.path("bin/tool");
+        std::fs::set_permissions(&tool, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let by_path = CommandDesc::new([&tool]).with_executable_identity().unwrap();
+        let relative = CommandDesc::new(["bin/tool"]).with_working_dir(dir.root())
+            .with_executable_identity().unwrap();
+        assert_eq!(by_path.executable, relative.executable);
+        assert_eq!(by_path.executable.as_ref().unwrap().path, tool.canonicalize().unwrap());
+
+        filetime::set_file_mtime(&tool, filetime::FileTime::from_unix_time(1000, 0)).unwrap();
+        let touched = CommandDesc::new([&tool]).with_executable_identity().unwrap();
+        assert_ne!(by_path.cache_key(), touched.cache_key());
+
+        assert!(CommandDesc::new(["bkt-no-such-program"]).with_executable_identity().is_err());
+    }
+
+    #[test]
+    fn git_state() {
+        use test_dir::{TestDir, DirBuilder, FileType};
+        let dir = TestDir::temp().create("repo/sub", FileType::Dir);
+        let repo = dir.path("repo");
+        let git = |args: &[&str]| {
+            let status = Command::new("git").arg("-C").arg(&repo)
+                .args(["-c", "user.name=bkt", "-c", "user.email=bkt@example.com"]).args(args)
+                .stdout(Stdio::null()).stderr(Stdio::null()).status().unwrap();
+            assert!(status.success(), "git {:?} failed", args);
+        };
+        let state = |dirty: bool| {
+            let cmd = CommandDesc::new(["true"]).with_working_dir(repo.join("sub"));
+            let cmd = if dirty { cmd.with_dirty_git_state() } else { cmd.with_git_state() };
+            cmd.unwrap().git_state.unwrap()
+        };
+        assert!(CommandDesc::new(["true"]).with_working_dir(dir.root()).with_git_state().is_err());
+
+        git(&["init", "-q", "-b", "main"]);
+        let unborn = state(false);
+        std::fs::write(repo.join("file"), "a").unwrap();
+        git(&["add", "file"]);
+        git(&["commit", "-q", "-m", "first"]);
+        let first = state(false);
+        assert_ne!(first.head, unborn.head);
+        assert_eq!(first.head.len(), 40);
+
+        std::fs::write(repo.join("file"), "b").unwrap();
+        git(&["commit", "-q", "-am", "second"]);
+        let second = state(false);
+        assert_ne!(second.head, first.head);
+        git(&["pack-refs", "--all"]);
+        assert_eq!(state(false).head, second.head);
+
+        let clean = state(true);
+        std::fs::write(repo.join("file"), "cc").unwrap();
+        let dirty = state(true);
+        assert_ne!(dirty.dirty, clean.dirty);
+        assert_eq!(dirty.head, clean.head);
+        std::fs::write(repo.join("file"), "ddd").unwrap();
+        assert_ne!(state(true).dirty, dirty.dirty);
+    }
 }
 
 /// The outputs of a cached invocation of a [`CommandDesc`], akin to [`std::process::Output`].
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct Invocation {
     stdout: Vec<u8>,
     stderr: Vec<u8>,
     exit_code: i32,
     runtime: Duration,
+    attempts: u32,
+    truncated: bool,
+    chunks: Option<Vec<OutputChunk>>,
+    #[serde(skip)]
+    streamed: bool,
+}
+
+// streamed describes how this particular Invocation was delivered, not its contents, and isn't
+// persisted, so it's ignored to keep a streamed result equal to the same result read from cache.
+impl PartialEq for Invocation {
+    fn eq(&self, other: &Self) -> bool {
+        self.stdout == other.stdout && self.stderr == other.stderr
+            && self.exit_code == other.exit_code && self.runtime == other.runtime
+            && self.attempts == other.attempts && self.truncated == other.truncated
+            && self.chunks == other.chunks
+    }
 }
 
+impl Eq for Invocation {}
+
 impl Invocation {
     /// The data that the process wrote to stdout.
     pub fn stdout(&self) -> &[u8] { &self.stdout }
@@ -251,86 +860,903 @@ impl Invocation {
     /// better support other termination states.
     pub fn exit_code(&self) -> i32 { self.exit_code }
 
-    /// The time the process took to complete.
+    /// The time the process took to complete, including any retries (see [`RetryPolicy`]) but not
+    /// the backoff between them.
     pub fn runtime(&self) -> Duration { self.runtime }
-}
 
-/// A file-lock mechanism that holds a lock by atomically creating a file in the given directory,
-/// and deleting the file upon being dropped. Callers should beware that dropping is not guaranteed
-/// (e.g. if the program panics). When a conflicting lock file is found its age (mtime) is checked
-/// to detect stale locks leaked by a separate process that failed to properly drop its lock.
-#[derive(Debug)]
-struct FileLock {
-    lock_file: PathBuf,
-}
+    /// The number of times the process was run to produce this result; more than one if earlier
+    /// attempts failed and were retried (see [`RetryPolicy`]).
+    pub fn attempts(&self) -> u32 { self.attempts }
 
-impl FileLock {
-    fn try_acquire<P: AsRef<Path>>(lock_dir: P, name: &str, consider_stale: Duration) -> Result<Option<Self>> {
-        let lock_file = lock_dir.as_ref().join(name).with_extension("lock");
-        match OpenOptions::new().create_new(true).write(true).open(&lock_file) {
-            Ok(mut lock) => {
-                write!(lock, "{}", std::process::id())?;
-                Ok(Some(FileLock{ lock_file }))
+    /// Whether the process's output exceeded the configured [`OutputLimit`], in which case only
+    /// part of it was captured.
+    pub fn truncated(&self) -> bool { self.truncated }
+
+    /// Whether the process's output was already written to stdout and stderr while it ran (see
+    /// [`Bkt::stream_output()`]), in which case callers should not write it again.
+    pub fn streamed(&self) -> bool { self.streamed }
+
+    /// Whether the order in which the process wrote to stdout and stderr was recorded (see
+    /// [`Bkt::record_interleaving()`]), so that [`replay()`](Invocation::replay()) can reproduce
+    /// it.
+    pub fn interleaved(&self) -> bool { self.chunks.is_some() }
+
+    /// Writes the process's output to the given writers. If the invocation is
+    /// [`interleaved()`](Invocation::interleaved()) output is written in the order it was
+    /// originally produced, and if `timed` is true with the same delays between each chunk as
+    /// when the process ran. Otherwise all of stdout is written followed by all of stderr.
+    pub fn replay(&self, stdout: &mut impl Write, stderr: &mut impl Write, timed: bool) -> io::Result<()> {
+        let chunks = match &self.chunks {
+            Some(chunks) => chunks,
+            None => {
+                stdout.write_all(&self.stdout)?;
+                stdout.flush()?;
+                return stderr.write_all(&self.stderr);
             },
-            Err(io) => {
-                match io.kind() {
-                    ErrorKind::AlreadyExists => {
-                        if let Ok(lock_metadata) = std::fs::metadata(&lock_file) {
-                            if let Ok(age) = lock_metadata.modified()?.elapsed() {
-                                if age > consider_stale {
-                                    return Err(Error::msg(format!(
-                                        "Lock {} held by PID {} appears stale and may need to be deleted manually.",
-                                        lock_file.display(),
-                                        std::fs::read_to_string(&lock_file).unwrap_or_else(|_| "unknown".into()))));
-                                }
-                            }
-                        }
-                        Ok(None)
-                    },
-                    _ => { Err(Error::new(io)) }
+        };
+        let start = Instant::now();
+        let (mut stdout_pos, mut stderr_pos) = (0, 0);
+        for chunk in chunks {
+            if timed {
+                if let Some(wait) = chunk.offset.checked_sub(start.elapsed()) {
+                    std::thread::sleep(wait);
                 }
-            },
+            }
+            let (out, data, pos): (&mut dyn Write, _, _) = match chunk.stream {
+                Stream::Stdout => (stdout, &self.stdout, &mut stdout_pos),
+                Stream::Stderr => (stderr, &self.stderr, &mut stderr_pos),
+            };
+            let end = (*pos + chunk.len).min(data.len());
+            out.write_all(&data[*pos..end])?;
+            out.flush()?;
+            *pos = end;
         }
+        Ok(())
     }
 }
 
-impl Drop for FileLock {
-    fn drop(&mut self) {
-        if let Err(e) = std::fs::remove_file(&self.lock_file) {
-            eprintln!("Failed to delete lockfile {}, may need to be deleted manually. Reason: {:?}",
-                      self.lock_file.display(), e);
-        }
-    }
+/// Limits how much output (stdout and stderr combined) Bkt captures from a command, to avoid
+/// exhausting memory or filling the cache directory. See [`Bkt::output_limit()`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct OutputLimit {
+    max_bytes: u64,
+    truncate: bool,
 }
 
-#[cfg(test)]
-mod file_lock_tests {
-    use super::*;
-    use test_dir::{TestDir, DirBuilder};
+impl OutputLimit {
+    /// Captures at most `max_bytes` of output. By default invocations that exceed the limit are
+    /// not cached.
+    pub fn new(max_bytes: u64) -> Self {
+        OutputLimit { max_bytes, truncate: false }
+    }
 
-    #[test]
-    fn locks() {
-        let dir = TestDir::temp();
-        let lock = FileLock::try_acquire(&dir.root(), "test", Duration::from_secs(100)).unwrap();
-        let lock = lock.expect("Could not take lock");
-        assert!(dir.path("test.lock").exists());
-        std::mem::drop(lock);
-        assert!(!dir.path("test.lock").exists());
+    /// Caches invocations that exceed the limit anyway, with their output truncated and ending in
+    /// a `[bkt: output truncated ...]` marker line.
+    pub fn truncate(mut self, truncate: bool) -> Self {
+        self.truncate = truncate;
+        self
     }
+}
 
-    #[test]
-    fn already_locked() {
-        let dir = TestDir::temp();
-        let lock = FileLock::try_acquire(&dir.root(), "test", Duration::from_secs(100)).unwrap();
-        let lock = lock.expect("Could not take lock");
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+enum Stream { Stdout, Stderr }
 
-        let attempt = FileLock::try_acquire(&dir.root(), "test", Duration::from_secs(100)).unwrap();
-        assert!(attempt.is_none());
+/// A contiguous piece of a subprocess's output. The data itself lives in the invocation's stdout
+/// or stderr buffer; chunks only record how those buffers were interleaved.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+struct OutputChunk {
+    stream: Stream,
+    len: usize,
+    // Time since the subprocess started
+    offset: Duration,
+}
 
-        std::mem::drop(lock);
-        let attempt = FileLock::try_acquire(&dir.root(), "test", Duration::from_secs(100)).unwrap();
+/// Accumulates a subprocess's output, up to an optional limit.
+struct CapturedOutput {
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    chunks: Option<Vec<OutputChunk>>,
+    limit: Option<OutputLimit>,
+    overflowed: Option<Stream>,
+    start: Instant,
+}
+
+impl CapturedOutput {
+    fn new(limit: Option<OutputLimit>, record_chunks: bool, start: Instant) -> Self {
+        let chunks = if record_chunks { Some(vec![]) } else { None };
+        CapturedOutput { stdout: vec![], stderr: vec![], chunks, limit, overflowed: None, start }
+    }
+
+    fn record(&mut self, stream: Stream, len: usize) {
+        if let (Some(chunks), true) = (&mut self.chunks, len > 0) {
+            chunks.push(OutputChunk { stream, len, offset: self.start.elapsed() });
+        }
+    }
+
+    fn push(&mut self, stream: Stream, chunk: &[u8]) {
+        let room = match self.limit {
+            Some(limit) => (limit.max_bytes as usize).saturating_sub(self.stdout.len() + self.stderr.len()),
+            None => chunk.len(),
+        };
+        if chunk.len() > room && self.overflowed.is_none() {
+            self.overflowed = Some(stream);
+        }
+        let buf = match stream {
+            Stream::Stdout => &mut self.stdout,
+            Stream::Stderr => &mut self.stderr,
+        };
+        let len = room.min(chunk.len());
+        buf.extend_from_slice(&chunk[..len]);
+        self.record(stream, len);
+    }
+
+    /// Appends a marker to the stream that exceeded the limit, if it should be truncated.
+    fn finish(&mut self) {
+        if let (Some(stream), Some(limit)) = (self.overflowed, self.limit) {
+            if limit.truncate {
+                let buf = if stream == Stream::Stdout { &mut self.stdout } else { &mut self.stderr };
+                let len = buf.len();
+                if !buf.is_empty() && !buf.ends_with(b"\n") {
+                    buf.push(b'\n');
+                }
+                buf.extend_from_slice(format!("[bkt: output truncated after {} bytes]\n", limit.max_bytes).as_bytes());
+                let added = buf.len() - len;
+                self.record(stream, added);
+            }
+        }
+    }
+}
+
+/// The controlling end of a pseudo-terminal, from which a subprocess's output is read. See
+/// [`CommandDesc::with_pty()`].
+struct PtyMaster(File);
+
+impl PtyMaster {
+    /// Opens a pseudo-terminal, returning its master end and the slave end to pass to the
+    /// subprocess.
+    #[cfg(unix)]
+    fn open() -> Result<(PtyMaster, File)> {
+        use std::os::unix::io::{AsRawFd, FromRawFd};
+        let (mut master, mut slave) = (0, 0);
+        let mut size = libc::winsize { ws_row: 24, ws_col: 80, ws_xpixel: 0, ws_ypixel: 0 };
+        // Use the current terminal's size if there is one; on failure the default is left as-is
+        unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) };
+        // Safe because the pointers are valid for the duration of the call
+        if unsafe { libc::openpty(&mut master, &mut slave, std::ptr::null_mut(), std::ptr::null_mut(),
+                                    &mut size as *mut libc::winsize) } != 0 {
+            return Err(Error::new(io::Error::last_os_error()).context("Failed to open pseudo-terminal"));
+        }
+        // Safe because openpty() succeeded, so both are open file descriptors we now own
+        let (master, slave) = unsafe { (File::from_raw_fd(master), File::from_raw_fd(slave)) };
+        for fd in [master.as_raw_fd(), slave.as_raw_fd()] {
+            // Don't leak either end into the subprocess, beyond the slave's use as its stdout
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+        }
+        // Don't translate \n into \r\n, so output matches what the command writes to a pipe
+        unsafe {
+            let mut termios = std::mem::zeroed::<libc::termios>();
+            if libc::tcgetattr(slave.as_raw_fd(), &mut termios) == 0 {
+                termios.c_oflag &= !libc::OPOST;
+                libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios);
+            }
+        }
+        Ok((PtyMaster(master), slave))
+    }
+
+    #[cfg(not(unix))]
+    fn open() -> Result<(PtyMaster, File)> {
+        Err(Error::msg("Running commands in a pseudo-terminal is only supported on Unix"))
+    }
+}
+
+impl io::Read for PtyMaster {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.0.read(buf) {
+            // Linux reports EIO rather than EOF once the subprocess closes the slave end
+            #[cfg(unix)]
+            Err(e) if e.raw_os_error() == Some(libc::EIO) => Ok(0),
+            result => result,
+        }
+    }
+}
+
+/// Configures whether and how [`Bkt`] re-runs commands that fail, before deciding what to cache.
+/// By default commands are not retried. See [`Bkt::retry_policy()`].
+///
+/// ```
+/// # use std::time::Duration;
+/// // Retry up to 3 times if the command exits with code 75 (EX_TEMPFAIL)
+/// let policy = bkt::RetryPolicy::default()
+///     .retries(3)
+///     .backoff(Duration::from_millis(500))
+///     .retry_on([75]);
+/// ```
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RetryPolicy {
+    retries: u32,
+    backoff: Duration,
+    retry_on: Vec<i32>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy { retries: 0, backoff: Duration::from_secs(1), retry_on: vec![] }
+    }
+}
+
+impl RetryPolicy {
+    /// The number of times to re-run a command that fails, so it will be run at most
+    /// `retries + 1` times. Defaults to 0.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// How long to wait before the first retry; the delay doubles after each subsequent attempt.
+    /// Defaults to 1 second.
+    pub fn backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Only retry commands that exit with one of the given codes, rather than any non-zero code.
+    pub fn retry_on<I: IntoIterator<Item=i32>>(mut self, exit_codes: I) -> Self {
+        self.retry_on = exit_codes.into_iter().collect();
+        self
+    }
+
+    fn should_retry(&self, result: &Invocation) -> bool {
+        result.attempts <= self.retries && result.exit_code != 0
+            && (self.retry_on.is_empty() || self.retry_on.contains(&result.exit_code))
+    }
+
+    fn delay(&self, attempt: u32) -> Duration {
+        self.backoff.saturating_mul(2u32.saturating_pow(attempt - 1))
+    }
+}
+
+/// A budget of executions allowed within a sliding window of time, such as once per ten seconds.
+/// See [`Bkt::rate_limit()`]. Can be parsed from strings like `"1/10s"` or `"5/1m"`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RateLimit {
+    executions: u32,
+    per: Duration,
+}
+
+impl RateLimit {
+    /// Allows at most `executions` executions within any window of length `per`.
+    ///
+    /// # Panics
+    ///
+    /// If `executions` or `per` is zero.
+    pub fn new(executions: u32, per: Duration) -> Self {
+        assert!(executions > 0, "executions cannot be zero");
+        assert!(per > Duration::ZERO, "per cannot be zero");
+        RateLimit { executions, per }
+    }
+}
+
+impl std::str::FromStr for RateLimit {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (executions, per) = s.split_once('/')
+            .ok_or_else(|| Error::msg(format!("Invalid rate '{}', expected e.g. 1/10s", s)))?;
+        let executions: u32 = executions.trim().parse()
+            .with_context(|| format!("Invalid number of executions '{}'", executions))?;
+        let per = humantime::parse_duration(per.trim())
+            .with_context(|| format!("Invalid duration '{}'", per))?;
+        if executions == 0 || per == Duration::ZERO {
+            return Err(Error::msg(format!("Invalid rate '{}', must be non-zero", s)));
+        }
+        Ok(RateLimit::new(executions, per))
+    }
+}
+
+impl std::fmt::Display for RateLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.executions, humantime::format_duration(self.per))
+    }
+}
+
+/// A set of wall-clock times, in the local time zone, at which cached results expire; see
+/// [`CommandDesc::with_expiry()`]. Can be parsed from `"midnight"` (or `"daily"`), `"hourly"`,
+/// `"weekly"` (midnight on Sundays), `"monthly"`, `"yearly"`, a time of day such as `"06:30"`, or a
+/// five-field cron expression such as `"0 9 * * 1-5"`. Cron fields (minute, hour, day of month,
+/// month, and day of week) support `*`, numbers, ranges, steps, and comma-separated lists, but not
+/// names. As in cron, if both the day of month and day of week are restricted either may match.
+///
+/// Schedules have minute granularity, and local times are only supported on Unix.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Schedule {
+    minutes: u64,
+    hours: u32,
+    days_of_month: u32,
+    months: u16,
+    days_of_week: u8,
+    any_day_of_month: bool,
+    any_day_of_week: bool,
+}
+
+impl Schedule {
+    /// Parses a cron field, returning the set of matching values as a bitmask.
+    fn parse_field(field: &str, min: u32, max: u32) -> Result<u64> {
+        let mut mask = 0;
+        for item in field.split(',') {
+            let (range, step) = match item.split_once('/') {
+                Some((range, step)) => (range, step.parse::<u32>().ok().filter(|&s| s > 0)
+                    .ok_or_else(|| Error::msg(format!("Invalid step '{}'", step)))?),
+                None => (item, 1),
+            };
+            let parse = |v: &str| v.parse::<u32>().ok().filter(|v| (min..=max).contains(v))
+                .ok_or_else(|| Error::msg(format!("Invalid value '{}', expected {}-{}", v, min, max)));
+            let (start, end) = match range.split_once('-') {
+                _ if range == "*" => (min, max),
+                Some((start, end)) => (parse(start)?, parse(end)?),
+                // As in cron, a step applies from the given value to the end of the range
+                None if step > 1 => (parse(range)?, max),
+                None => (parse(range)?, parse(range)?),
+            };
+            if start > end {
+                return Err(Error::msg(format!("Invalid range '{}'", range)));
+            }
+            for value in (start..=end).step_by(step as usize) {
+                mask |= 1 << value;
+            }
+        }
+        Ok(mask)
+    }
+
+    #[cfg(unix)]
+    fn matches_day(&self, tm: &libc::tm) -> bool {
+        let day_of_month = self.days_of_month & (1 << tm.tm_mday) != 0;
+        let day_of_week = self.days_of_week & (1 << tm.tm_wday) != 0;
+        let day = match (self.any_day_of_month, self.any_day_of_week) {
+            (false, false) => day_of_month || day_of_week,
+            _ => day_of_month && day_of_week,
+        };
+        day && self.months & (1 << (tm.tm_mon + 1)) != 0
+    }
+
+    /// Searches forward (or backward) from the start of the minute containing `time` for a
+    /// matching minute, skipping over non-matching days and hours at a time.
+    #[cfg(unix)]
+    fn find(&self, time: SystemTime, forward: bool) -> Result<SystemTime> {
+        let secs = time.duration_since(std::time::UNIX_EPOCH)?.as_secs() as i64;
+        let mut t = secs - secs % 60;
+        if forward {
+            t += 60;
+        }
+        // Enough to span several years, e.g. to find the next February 29th
+        for _ in 0..200_000 {
+            let mut tm = local_time(t);
+            let next_minute = if forward {
+                (tm.tm_min + 1..60).find(|&m| self.minutes & (1 << m) != 0)
+            } else {
+                (0..tm.tm_min).rev().find(|&m| self.minutes & (1 << m) != 0)
+            };
+            // Skip non-matching days and hours (to the start of the next one, or the end of the
+            // previous one) and minutes, until a matching minute is found
+            if !self.matches_day(&tm) {
+                tm.tm_mday += forward as i32;
+                tm.tm_hour = 0;
+            } else if self.hours & (1 << tm.tm_hour) == 0 {
+                tm.tm_hour += forward as i32;
+            } else if self.minutes & (1 << tm.tm_min) != 0 {
+                return Ok(std::time::UNIX_EPOCH + Duration::from_secs(t as u64));
+            } else if let Some(minute) = next_minute {
+                t += (minute - tm.tm_min) as i64 * 60;
+                continue;
+            } else {
+                tm.tm_hour += forward as i32;
+            }
+            tm.tm_min = 0;
+            tm.tm_sec = 0;
+            // Around daylight saving time transitions mktime() may not land exactly there, so always
+            // make some progress
+            t = if forward { local_mktime(tm).max(t + 60) } else { (local_mktime(tm) - 60).min(t - 60) };
+        }
+        Err(Error::msg("No matching time found for schedule"))
+    }
+
+    #[cfg(not(unix))]
+    fn find(&self, _time: SystemTime, _forward: bool) -> Result<SystemTime> {
+        Err(Error::msg("Expiry schedules are only supported on Unix"))
+    }
+
+    /// The most recent time matching this schedule at or before `time`.
+    ///
+    /// # Errors
+    ///
+    /// If no matching time is found within several years, e.g. for February 30th, or on platforms
+    /// other than Unix.
+    pub fn previous(&self, time: SystemTime) -> Result<SystemTime> {
+        self.find(time, false)
+    }
+
+    /// The first time matching this schedule after `time`.
+    ///
+    /// # Errors
+    ///
+    /// See [`Schedule::previous()`].
+    pub fn next(&self, time: SystemTime) -> Result<SystemTime> {
+        self.find(time, true)
+    }
+}
+
+impl std::str::FromStr for Schedule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let cron = match s {
+            "midnight" | "daily" => "0 0 * * *".into(),
+            "hourly" => "0 * * * *".into(),
+            "weekly" => "0 0 * * 0".into(),
+            "monthly" => "0 0 1 * *".into(),
+            "yearly" => "0 0 1 1 *".into(),
+            _ => match s.split_once(':') {
+                Some((hour, minute)) => format!("{} {} * * *", minute, hour),
+                None => s.into(),
+            },
+        };
+        let fields: Vec<_> = cron.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(Error::msg(format!("Invalid schedule '{}', expected e.g. midnight, 06:30, or 0 9 * * 1-5", s)));
+        }
+        let context = || format!("Invalid schedule '{}'", s);
+        let days_of_week = Schedule::parse_field(fields[4], 0, 7).with_context(context)?;
+        Ok(Schedule {
+            minutes: Schedule::parse_field(fields[0], 0, 59).with_context(context)?,
+            hours: Schedule::parse_field(fields[1], 0, 23).with_context(context)? as u32,
+            days_of_month: Schedule::parse_field(fields[2], 1, 31).with_context(context)? as u32,
+            months: Schedule::parse_field(fields[3], 1, 12).with_context(context)? as u16,
+            // Both 0 and 7 are Sunday
+            days_of_week: (days_of_week | days_of_week >> 7) as u8 & 0x7F,
+            // As in cron, only fields starting with * are unrestricted
+            any_day_of_month: fields[2].starts_with('*'),
+            any_day_of_week: fields[4].starts_with('*'),
+        })
+    }
+}
+
+#[cfg(unix)]
+fn local_time(time: i64) -> libc::tm {
+    // Safe because tm is plain data, and localtime_r() only writes to the given struct
+    unsafe {
+        let mut tm: libc::tm = std::mem::zeroed();
+        libc::localtime_r(&(time as libc::time_t), &mut tm);
+        tm
+    }
+}
+
+#[cfg(unix)]
+fn local_mktime(mut tm: libc::tm) -> i64 {
+    // Let mktime() determine whether daylight saving time is in effect
+    tm.tm_isdst = -1;
+    unsafe { libc::mktime(&mut tm) as i64 }
+}
+
+/// The error returned (wrapped in an [`anyhow::Error`]) when a command must be executed but doing so
+/// would exceed a configured [`RateLimit`], and there is no previously cached result to return
+/// instead.
+#[derive(Debug)]
+pub struct RateLimited {
+    retry_after: Duration,
+}
+
+impl RateLimited {
+    /// How long until the command can be executed again.
+    pub fn retry_after(&self) -> Duration { self.retry_after }
+}
+
+impl std::fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Rate limited, no cached result is available; retry in {}",
+               humantime::format_duration(Duration::from_secs(Cache::seconds_ceiling(self.retry_after))))
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+/// Usage statistics for a cached [`CommandDesc`], useful for judging whether caching a command is
+/// worthwhile. See [`Bkt::stats()`].
+#[derive(Clone, Debug, Default, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+pub struct CommandStats {
+    hits: u64,
+    misses: u64,
+    refreshes: u64,
+    failures: u64,
+    saved: Duration,
+}
+
+impl CommandStats {
+    /// The number of lookups that were served from the cache.
+    pub fn hits(&self) -> u64 { self.hits }
+
+    /// The number of lookups that were not found in the cache (or had expired), causing the
+    /// command to be executed.
+    pub fn misses(&self) -> u64 { self.misses }
+
+    /// The number of explicit refreshes via [`Bkt::refresh()`], such as those triggered by the
+    /// `bkt` binary's `--stale`, `--warm`, and `--force` flags.
+    pub fn refreshes(&self) -> u64 { self.refreshes }
+
+    /// The number of executions (due to misses or refreshes) that returned a non-zero exit code.
+    pub fn failures(&self) -> u64 { self.failures }
+
+    /// The total runtime avoided by serving cache hits, based on the runtime of the cached
+    /// invocations. This does not account for the overhead of the cache lookups themselves.
+    pub fn saved_runtime(&self) -> Duration { self.saved }
+}
+
+/// Configures how Bkt cleans up stale data in its cache directory. See [`Bkt::cleanup_policy()`].
+///
+/// ```
+/// # use std::time::Duration;
+/// let policy = bkt::CleanupPolicy::default()
+///     .throttle(Duration::from_secs(300))
+///     .poll_interval(Duration::from_secs(600));
+/// ```
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CleanupPolicy {
+    throttle: Duration,
+    stale_lock: Duration,
+    poll_interval: Duration,
+    stats_retention: Duration,
+}
+
+impl Default for CleanupPolicy {
+    fn default() -> Self {
+        CleanupPolicy {
+            throttle: Duration::from_secs(30),
+            stale_lock: Duration::from_secs(60*10),
+            poll_interval: Duration::from_secs(60),
+            stats_retention: Duration::from_secs(60*60*24*30),
+        }
+    }
+}
+
+impl CleanupPolicy {
+    /// The minimum time between cleanups of the same cache directory, by any process; more
+    /// frequent cleanups are skipped. Defaults to 30 seconds.
+    pub fn throttle(mut self, throttle: Duration) -> Self {
+        self.throttle = throttle;
+        self
+    }
+
+    /// How old a cleanup lock held by a process that can't be confirmed dead must be before it is
+    /// reported as stale (as an error). This only applies on filesystems that don't support
+    /// `flock()`, where locks are held by the existence of a lock file. Defaults to 10 minutes.
+    pub fn stale_lock_after(mut self, stale_lock: Duration) -> Self {
+        self.stale_lock = stale_lock;
+        self
+    }
+
+    /// How often [`Bkt::cleanup_thread()`] attempts a cleanup. Defaults to 60 seconds.
+    pub fn poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// How long usage statistics (see [`Bkt::stats()`]) are kept after they were last updated,
+    /// once the command's result is no longer cached. Defaults to 30 days.
+    pub fn stats_retention(mut self, stats_retention: Duration) -> Self {
+        self.stats_retention = stats_retention;
+        self
+    }
+}
+
+/// The files removed by a cleanup of the cache. See [`Bkt::gc()`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CleanupReport {
+    files: u64,
+    bytes: u64,
+}
+
+impl CleanupReport {
+    /// The number of files (including symlinks) that were removed.
+    pub fn files_removed(&self) -> u64 { self.files }
+
+    /// The total size of the files that were removed.
+    pub fn bytes_reclaimed(&self) -> u64 { self.bytes }
+
+    fn record(&mut self, path: &Path) -> bool {
+        // Check the size first, since it can't be checked after the file is removed. Only regular
+        // files count towards the bytes reclaimed; a symlink's length is just its target's path.
+        let size = std::fs::symlink_metadata(path)
+            .map(|m| if m.file_type().is_file() { m.len() } else { 0 }).unwrap_or(0);
+        if std::fs::remove_file(path).is_ok() {
+            trace!("cleanup removed {}", path.display());
+            self.files += 1;
+            self.bytes += size;
+            return true;
+        }
+        false
+    }
+}
+
+/// A file-lock mechanism that holds a lock on a file in the given directory, deleting the file upon
+/// being dropped. Where supported the lock is an advisory `flock()` on the file, which the OS
+/// releases if the holding process dies without dropping the lock, so a leftover lock file is
+/// simply taken over by the next caller. Otherwise the lock is held by atomically creating the
+/// file, and a conflicting lock file records the host and PID of its holder so that locks leaked by
+/// a process that has since died on this host can be stolen. Lock files that can't be confirmed
+/// abandoned this way are checked for their age (mtime) to detect stale locks.
+#[derive(Debug)]
+struct FileLock {
+    lock_file: PathBuf,
+    // Kept open since closing the file would release its flock()
+    _file: File,
+}
+
+impl FileLock {
+    fn try_acquire<P: AsRef<Path>>(lock_dir: P, name: &str, consider_stale: Duration) -> Result<Option<Self>> {
+        let lock_file = lock_dir.as_ref().join(name).with_extension("lock");
+        match OpenOptions::new().create_new(true).write(true).open(&lock_file) {
+            Ok(file) => {
+                // Another process may have opened and flock()-ed the new file before we could
+                if !FileLock::flock(&file)?.unwrap_or(true) {
+                    debug!("lock {} taken over", lock_file.display());
+                    return Ok(None);
+                }
+                FileLock::hold(lock_file, file).map(Some)
+            },
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                let file = match OpenOptions::new().write(true).open(&lock_file) {
+                    Ok(file) => file,
+                    // Released in the meantime; just try again later
+                    Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+                    Err(e) => return Err(Error::new(e)),
+                };
+                match FileLock::flock(&file)? {
+                    Some(true) => {
+                        // The holder may have deleted the file between our open() and flock()
+                        if !FileLock::is_same_file(&file, &lock_file)? {
+                            debug!("lock {} released", lock_file.display());
+                            return Ok(None);
+                        }
+                        debug!("lock {} abandoned, taking over", lock_file.display());
+                        FileLock::hold(lock_file, file).map(Some)
+                    },
+                    Some(false) => {
+                        debug!("lock {} already held", lock_file.display());
+                        Ok(None)
+                    },
+                    None => {
+                        std::mem::drop(file);
+                        FileLock::try_steal(lock_file, consider_stale)
+                    },
+                }
+            },
+            Err(e) => Err(Error::new(e)),
+        }
+    }
+
+    /// Like try_acquire(), but waits up to `timeout` for the lock to be released.
+    fn acquire<P: AsRef<Path>>(lock_dir: P, name: &str, consider_stale: Duration, timeout: Duration) -> Result<Self> {
+        let start = Instant::now();
+        loop {
+            if let Some(lock) = FileLock::try_acquire(&lock_dir, name, consider_stale)? {
+                return Ok(lock);
+            }
+            if start.elapsed() > timeout {
+                return Err(Error::msg(format!("Timed out waiting for lock {}",
+                                              lock_dir.as_ref().join(name).display())));
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn hold(lock_file: PathBuf, mut file: File) -> Result<Self> {
+        file.set_len(0)?;
+        write!(file, "{}", FileLock::owner())?;
+        Ok(FileLock{ lock_file, _file: file })
+    }
+
+    /// Identifies this process in the lock file, as `host:pid`.
+    fn owner() -> String {
+        format!("{}:{}", FileLock::hostname(), std::process::id())
+    }
+
+    /// Attempts to take the fallback lock held by the lock file's existence, stealing it if its
+    /// holder is known to be dead.
+    fn try_steal(lock_file: PathBuf, consider_stale: Duration) -> Result<Option<Self>> {
+        let owner = match std::fs::read_to_string(&lock_file) {
+            Ok(owner) => owner,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(Error::new(e)),
+        };
+        let dead = owner.rsplit_once(':')
+            .filter(|(host, _)| *host == FileLock::hostname())
+            .and_then(|(_, pid)| pid.parse().ok())
+            .map(|pid| !FileLock::is_alive(pid))
+            .unwrap_or(false);
+        if dead {
+            // Move the lock file aside before deleting it, so we can confirm it's the one we
+            // inspected and not a new lock taken by a concurrent steal
+            let stolen = Cache::rand_filename(lock_file.parent().expect("Has parent"), "stolen-lock");
+            match std::fs::rename(&lock_file, &stolen) {
+                Ok(_) => {},
+                Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(Error::new(e)),
+            }
+            let confirmed = std::fs::read_to_string(&stolen).map(|o| o == owner).unwrap_or(false);
+            if !confirmed {
+                // Put the new lock back, unless yet another lock was taken in the meantime
+                let _ = std::fs::hard_link(&stolen, &lock_file);
+            }
+            std::fs::remove_file(&stolen)?;
+            if !confirmed {
+                return Ok(None);
+            }
+            warn!("stole lock {} from dead process {}", lock_file.display(), owner);
+            return match OpenOptions::new().create_new(true).write(true).open(&lock_file) {
+                Ok(file) => FileLock::hold(lock_file, file).map(Some),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => Ok(None),
+                Err(e) => Err(Error::new(e)),
+            };
+        }
+        debug!("lock {} already held", lock_file.display());
+        if let Ok(lock_metadata) = std::fs::metadata(&lock_file) {
+            if let Ok(age) = lock_metadata.modified()?.elapsed() {
+                if age > consider_stale {
+                    return Err(Error::msg(format!(
+                        "Lock {} held by {} appears stale and may need to be deleted manually.",
+                        lock_file.display(), if owner.is_empty() { "unknown" } else { &owner })));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Attempts to take an exclusive flock() on the file without blocking, returning whether it
+    /// was acquired, or None if the filesystem doesn't support flock().
+    #[cfg(unix)]
+    fn flock(file: &File) -> Result<Option<bool>> {
+        use std::os::unix::io::AsRawFd;
+        // Safe because the fd is valid for the lifetime of `file`
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+            return Ok(Some(true));
+        }
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::EWOULDBLOCK) => Ok(Some(false)),
+            Some(libc::ENOLCK) | Some(libc::EOPNOTSUPP) | Some(libc::EINVAL) => {
+                debug!("flock unsupported, falling back to lock file: {}", err);
+                Ok(None)
+            },
+            _ => Err(Error::new(err).context("flock failed")),
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn flock(_file: &File) -> Result<Option<bool>> { Ok(None) }
+
+    #[cfg(unix)]
+    fn is_same_file(file: &File, path: &Path) -> Result<bool> {
+        use std::os::unix::fs::MetadataExt;
+        let (held, current) = match std::fs::metadata(path) {
+            Ok(current) => (file.metadata()?, current),
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(Error::new(e)),
+        };
+        Ok(held.dev() == current.dev() && held.ino() == current.ino())
+    }
+
+    #[cfg(not(unix))]
+    fn is_same_file(_file: &File, path: &Path) -> Result<bool> { Ok(path.exists()) }
+
+    #[cfg(unix)]
+    fn is_alive(pid: i32) -> bool {
+        // Signal 0 only checks whether the process exists; EPERM means it exists as another user
+        let signaled = unsafe { libc::kill(pid, 0) };
+        signaled == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+    }
+
+    // Liveness can't be checked, so assume the holder is alive and rely on the lock's age
+    #[cfg(not(unix))]
+    fn is_alive(_pid: i32) -> bool { true }
+
+    #[cfg(unix)]
+    fn hostname() -> String {
+        let mut buf = [0u8; 256];
+        // Safe because the length passed is the buffer's length
+        if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+            return "unknown".into();
+        }
+        let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+        String::from_utf8_lossy(&buf[..len]).into()
+    }
+
+    #[cfg(not(unix))]
+    fn hostname() -> String {
+        std::env::var("COMPUTERNAME").unwrap_or_else(|_| "unknown".into())
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        // Deleted while still flock()-ed, see try_acquire()'s is_same_file() check
+        if let Err(e) = std::fs::remove_file(&self.lock_file) {
+            warn!("Failed to delete lockfile {}, may need to be deleted manually. Reason: {:?}",
+                  self.lock_file.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::needless_borrows_for_generic_args)]
+mod file_lock_tests {
+    use super::*;
+    use test_dir::{TestDir, DirBuilder};
+
+    #[test]
+    fn locks() {
+        let dir = TestDir::temp();
+        let lock = FileLock::try_acquire(&dir.root(), "test", Duration::from_secs(100)).unwrap();
+        let lock = lock.expect("Could not take lock");
+        assert!(dir.path("test.lock").exists());
+        std::mem::drop(lock);
+        assert!(!dir.path("test.lock").exists());
+    }
+
+    #[test]
+    fn already_locked() {
+        let dir = TestDir::temp();
+        let lock = FileLock::try_acquire(&dir.root(), "test", Duration::from_secs(100)).unwrap();
+        let lock = lock.expect("Could not take lock");
+
+        let attempt = FileLock::try_acquire(&dir.root(), "test", Duration::from_secs(100)).unwrap();
+        assert!(attempt.is_none());
+
+        std::mem::drop(lock);
+        let attempt = FileLock::try_acquire(&dir.root(), "test", Duration::from_secs(100)).unwrap();
         assert!(attempt.is_some());
     }
+
+    fn dead_pid() -> u32 {
+        let mut child = Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        child.id()
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn abandoned() {
+        // A lock file left behind by a crashed process isn't flock()-ed by anyone
+        let dir = TestDir::temp();
+        std::fs::write(dir.path("test.lock"), format!("{}:{}", FileLock::hostname(), dead_pid())).unwrap();
+        let lock = FileLock::try_acquire(dir.root(), "test", Duration::from_secs(100)).unwrap();
+        assert!(lock.is_some());
+        assert_eq!(std::fs::read_to_string(dir.path("test.lock")).unwrap(), FileLock::owner());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn steals_from_dead_process() {
+        let dir = TestDir::temp();
+        std::fs::write(dir.path("test.lock"), format!("{}:{}", FileLock::hostname(), dead_pid())).unwrap();
+        let lock = FileLock::try_steal(dir.path("test.lock"), Duration::from_secs(100)).unwrap();
+        assert!(lock.is_some());
+        assert_eq!(std::fs::read_to_string(dir.path("test.lock")).unwrap(), FileLock::owner());
+        // only the lock file itself remains
+        assert_eq!(std::fs::read_dir(dir.root()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn does_not_steal_from_live_process() {
+        let dir = TestDir::temp();
+        std::fs::write(dir.path("test.lock"), FileLock::owner()).unwrap();
+        let lock = FileLock::try_steal(dir.path("test.lock"), Duration::from_secs(100)).unwrap();
+        assert!(lock.is_none());
+
+        std::fs::write(dir.path("test.lock"), format!("otherhost:{}", dead_pid())).unwrap();
+        let lock = FileLock::try_steal(dir.path("test.lock"), Duration::from_secs(100)).unwrap();
+        assert!(lock.is_none());
+        let stale = FileLock::try_steal(dir.path("test.lock"), Duration::ZERO);
+        assert!(stale.is_err());
+    }
 }
 
 /// Trait allowing a type to be used as a cache key. It would be nice to blanket-implement
@@ -369,6 +1795,118 @@ struct CacheEntry<K, V> {
     value: V,
 }
 
+/// A portable snapshot of a cache's contents, see `Cache::export()`.
+#[derive(Serialize, Deserialize)]
+struct CacheArchive<K, V> {
+    created: SystemTime,
+    entries: Vec<ArchivedEntry<K, V>>,
+}
+
+/// The recent executions counted against a rate limit, see `Cache::consume_rate_budgets()`.
+#[derive(Default, Serialize, Deserialize)]
+struct RateState {
+    window: Duration,
+    executions: Vec<SystemTime>,
+}
+
+/// A cached command and the paths it depends on, see `Bkt::register_watch()`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct WatchEntry {
+    command: CommandDesc,
+    // The directory register_watch() was called from, where refreshes are run
+    cwd: PathBuf,
+    paths: Vec<PathBuf>,
+    ttl: Duration,
+    scope: Option<String>,
+    tags: Vec<String>,
+    on_change: OnChange,
+}
+
+/// What [`Bkt::watch()`] does when a path a cached command depends on changes, see
+/// [`Bkt::register_watch()`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum OnChange {
+    /// Removes the cached result, so the next lookup executes the command.
+    Invalidate,
+    /// Executes the command again and caches the new result, so the next lookup is fast.
+    Refresh,
+}
+
+/// Reports changes to files and directories, using Linux's inotify API.
+#[cfg(target_os = "linux")]
+struct Inotify {
+    fd: File,
+    watches: BTreeMap<i32, PathBuf>,
+}
+
+#[cfg(target_os = "linux")]
+impl Inotify {
+    fn new() -> Result<Self> {
+        use std::os::unix::io::FromRawFd;
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(Error::new(io::Error::last_os_error()).context("Failed to initialize inotify"));
+        }
+        // Safe because inotify_init1() succeeded, so this is an open file descriptor we now own
+        Ok(Inotify { fd: unsafe { File::from_raw_fd(fd) }, watches: BTreeMap::new() })
+    }
+
+    /// Watches the given directory for changes to it or its immediate contents.
+    fn add(&mut self, dir: &Path) -> Result<()> {
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::io::AsRawFd;
+        let path = std::ffi::CString::new(dir.as_os_str().as_bytes())?;
+        let mask = libc::IN_MODIFY | libc::IN_ATTRIB | libc::IN_CLOSE_WRITE | libc::IN_CREATE | libc::IN_DELETE
+            | libc::IN_MOVED_FROM | libc::IN_MOVED_TO | libc::IN_DELETE_SELF | libc::IN_MOVE_SELF;
+        let wd = unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), path.as_ptr(), mask) };
+        if wd < 0 {
+            return Err(Error::new(io::Error::last_os_error()).context(format!("Failed to watch {}", dir.display())));
+        }
+        self.watches.insert(wd, dir.into());
+        Ok(())
+    }
+
+    /// Waits up to `timeout` for changes, returning the paths that changed, if any.
+    fn read(&mut self, timeout: Duration) -> Result<Vec<PathBuf>> {
+        use std::io::Read;
+        use std::os::unix::io::AsRawFd;
+        let mut poll = libc::pollfd { fd: self.fd.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        if unsafe { libc::poll(&mut poll, 1, timeout.as_millis() as libc::c_int) } <= 0 {
+            // Timed out, or interrupted by a signal
+            return Ok(vec![]);
+        }
+        let mut buf = [0u8; 8192];
+        let len = self.fd.read(&mut buf).context("Failed to read inotify events")?;
+        let header = std::mem::size_of::<libc::inotify_event>();
+        let mut changed = Vec::new();
+        let mut offset = 0;
+        while offset + header <= len {
+            // Safe because the kernel writes whole events, and read_unaligned() handles alignment
+            let event = unsafe { std::ptr::read_unaligned(buf[offset..].as_ptr() as *const libc::inotify_event) };
+            let name = &buf[offset + header..offset + header + event.len as usize];
+            let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+            if let Some(dir) = self.watches.get(&event.wd) {
+                changed.push(if name.is_empty() { dir.clone() } else {
+                    use std::os::unix::ffi::OsStrExt;
+                    dir.join(OsStr::from_bytes(name))
+                });
+            }
+            offset += header + event.len as usize;
+        }
+        Ok(changed)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct ArchivedEntry<K, V> {
+    key: K,
+    value: V,
+    scope: Option<String>,
+    stored: SystemTime,
+    ttl: Duration,
+    tags: Vec<String>,
+}
+
 // See https://doc.rust-lang.org/std/fs/fn.soft_link.html
 #[cfg(windows)]
 fn symlink<P: AsRef<Path>, Q: AsRef<Path>>(original: P, link: Q) -> Result<()> {
@@ -385,11 +1923,39 @@ use std::os::unix::fs::symlink;
 struct Cache {
     cache_dir: PathBuf,
     scope: Option<String>,
+    tags: Vec<String>,
+    read_only: bool,
+    max_ttl: Option<Duration>,
 }
 
 impl Cache {
     fn new<P: AsRef<Path>>(cache_dir: P) -> Self {
-        Cache{ cache_dir: cache_dir.as_ref().into(), scope: None }
+        Cache{ cache_dir: cache_dir.as_ref().into(), scope: None, tags: vec![], read_only: false, max_ttl: None }
+    }
+
+    /// A read-only cache never modifies the cache directory; lookups leave expired data in place
+    /// and cleanups are no-ops, while other operations that would write fail.
+    fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Caps how long entries in this cache are considered valid, regardless of the TTL requested
+    /// by callers of lookup() and store().
+    fn max_ttl(mut self, max_ttl: Option<Duration>) -> Self {
+        self.max_ttl = max_ttl;
+        self
+    }
+
+    fn capped_ttl(&self, ttl: Duration) -> Duration {
+        self.max_ttl.map_or(ttl, |max_ttl| max_ttl.min(ttl))
+    }
+
+    fn ensure_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(Error::msg(format!("Cache {} is read-only", self.cache_dir.display())));
+        }
+        Ok(())
     }
 
     fn scoped(mut self, scope: String) -> Self {
@@ -398,6 +1964,19 @@ impl Cache {
         self
     }
 
+    fn tagged(mut self, tag: String) -> Self {
+        self.tags.push(tag);
+        self
+    }
+
+    /// Tags are used as file names, so they must not be empty, `.` or `..`, or contain separators.
+    fn check_tag(tag: &str) -> Result<()> {
+        if tag.is_empty() || tag == "." || tag == ".." || tag.contains(std::path::is_separator) {
+            return Err(Error::msg(format!("Invalid tag '{}'", tag)));
+        }
+        Ok(())
+    }
+
     #[cfg(not(feature = "debug"))]
     fn serialize<W, T>(writer: W, value: &T) -> Result<()>
             where W: io::Write, T: Serialize + ?Sized {
@@ -426,27 +2005,95 @@ impl Cache {
         self.cache_dir.join("keys")
     }
 
-    fn key_path(&self, key: &str) -> PathBuf {
-        let file = match &self.scope {
+    /// The scope-qualified file name used for the given key, shared by the key and stats dirs.
+    fn key_file(&self, key: &str) -> String {
+        match &self.scope {
             Some(scope) => format!("{}.{}", scope, key),
             None => key.into(),
-        };
-        self.key_dir().join(file)
+        }
+    }
+
+    /// Whether the given key file name belongs to this cache's scope. Cache keys never contain a
+    /// `.` so an unscoped file has none and a scoped file has exactly the scope before the key.
+    fn in_scope(&self, file_name: &str) -> bool {
+        match &self.scope {
+            Some(scope) => file_name.strip_prefix(scope.as_str())
+                .and_then(|s| s.strip_prefix('.'))
+                .map(|k| !k.contains('.'))
+                .unwrap_or(false),
+            None => !file_name.contains('.'),
+        }
+    }
+
+    fn key_path(&self, key: &str) -> PathBuf {
+        self.key_dir().join(self.key_file(key))
     }
 
     fn data_dir(&self) -> PathBuf {
         self.cache_dir.join("data")
     }
 
+    fn stats_dir(&self) -> PathBuf {
+        self.cache_dir.join("stats")
+    }
+
+    /// Returns the random salt used to hash secrets stored in this cache, creating it if needed.
+    fn secret_salt(&self) -> Result<Vec<u8>> {
+        let path = self.cache_dir.join("secret_salt");
+        match std::fs::read(&path) {
+            Ok(salt) if !salt.is_empty() => return Ok(salt),
+            Ok(_) => {}, // a concurrent process is still writing it
+            Err(e) if e.kind() == ErrorKind::NotFound => {},
+            Err(e) => return Err(Error::new(e).context("Failed to read secret salt")),
+        }
+        if self.read_only {
+            // No secrets can have been stored without a salt, so any salt will do
+            return Ok(rand::random::<[u8; 32]>().to_vec());
+        }
+        // Write the salt to a temp file and hard-link it into place, so it is only visible once
+        // complete and an existing salt is never overwritten
+        let tmp_path = Cache::rand_filename(&self.cache_dir, "tmp-salt");
+        std::fs::write(&tmp_path, rand::random::<[u8; 32]>())?;
+        let linked = std::fs::hard_link(&tmp_path, &path);
+        std::fs::remove_file(&tmp_path)?;
+        match linked {
+            Ok(_) => debug!("created secret salt {}", path.display()),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {},
+            Err(e) => return Err(Error::new(e).context("Failed to create secret salt")),
+        }
+        Ok(std::fs::read(&path)?)
+    }
+
+    fn tag_dir(&self, tag: &str) -> Result<PathBuf> {
+        Cache::check_tag(tag)?;
+        Ok(self.cache_dir.join("tags").join(tag))
+    }
+
     /// Looks up the given key in the cache, returning the associated value and its age
     /// if the data is found and is newer than the max_age.
     fn lookup<K, V>(&self, key: &K, max_age: Duration) -> Result<Option<(V, SystemTime)>>
             where K: CacheKey+DeserializeOwned, V: DeserializeOwned {
+        self.lookup_with_max_age(key, Some(max_age))
+    }
+
+    /// Whether an entry, expired or not, is stored for the given key.
+    fn contains<K: CacheKey>(&self, key: &K) -> bool {
+        self.key_path(&key.cache_key()).exists()
+    }
+
+    /// Like lookup() but returns the entry regardless of its age, without removing it if expired.
+    fn lookup_ignoring_ttl<K, V>(&self, key: &K) -> Result<Option<(V, SystemTime)>>
+            where K: CacheKey+DeserializeOwned, V: DeserializeOwned {
+        self.lookup_with_max_age(key, None)
+    }
+
+    fn lookup_with_max_age<K, V>(&self, key: &K, max_age: Option<Duration>) -> Result<Option<(V, SystemTime)>>
+            where K: CacheKey+DeserializeOwned, V: DeserializeOwned {
         let path = self.key_path(&key.cache_key());
         let file = File::open(&path);
         if let Err(ref e) = file {
             if e.kind() == ErrorKind::NotFound {
-                debug_msg!("lookup {} not found", path.display());
+                debug!("lookup {} not found", path.display());
                 return Ok(None);
             }
         }
@@ -458,17 +2105,20 @@ impl Cache {
         // Discard data that is too old
         let mtime = std::fs::metadata(&path)?.modified()?;
         let elapsed = mtime.elapsed();
-        if elapsed.is_err() || elapsed.unwrap() > max_age {
-            debug_msg!("lookup {} expired", path.display());
-            std::fs::remove_file(&path).context("Failed to remove expired data")?;
+        let expired = max_age.map(|max_age| elapsed.is_err() || elapsed.unwrap() > self.capped_ttl(max_age));
+        if expired.unwrap_or(false) {
+            debug!("lookup {} expired", path.display());
+            if !self.read_only {
+                std::fs::remove_file(&path).context("Failed to remove expired data")?;
+            }
             return Ok(None);
         }
         // Ignore false-positive hits that happened to collide with the hash code
         if &found.key != key {
-            debug_msg!("lookup {} hash collision", path.display());
+            debug!("lookup {} hash collision", path.display());
             return Ok(None);
         }
-        debug_msg!("lookup {} found", path.display());
+        debug!("lookup {} found", path.display());
         Ok(Some((found.value, mtime)))
     }
 
@@ -484,54 +2134,431 @@ impl Cache {
         dir.join(format!("{}.{}", label, rand_str))
     }
 
-    /// Write the given key/value pair to the cache, persisting it for at least the given TTL.
-    fn store<K, V>(&self, key: &K, value: &V, ttl: Duration) -> Result<()>
-            where K: CacheKey+Serialize, V: Serialize {
-        assert!(!ttl.as_secs() > 0 || ttl.subsec_nanos() > 0, "ttl cannot be zero"); // TODO use is_zero once stable
-        let ttl_dir = self.data_dir().join(Cache::seconds_ceiling(ttl).to_string());
-        std::fs::create_dir_all(&ttl_dir)?;
-        std::fs::create_dir_all(&self.key_dir())?;
-        let path = Cache::rand_filename(&ttl_dir, "data");
-        // Note: this will fail if filename collides, could retry in a loop if that happens
-        let file = OpenOptions::new().create_new(true).write(true).open(&path)?;
-        let entry = CacheEntry{ key, value };
-        Cache::serialize(BufWriter::new(&file), &entry).context("Serialization failed")?;
-        debug_msg!("store data {}", path.display());
-        // Roundabout approach to an atomic symlink replacement
-        // https://github.com/dimo414/bash-cache/issues/26
-        let tmp_symlink = Cache::rand_filename(&self.key_dir(), "tmp-symlink");
-        // Note: this will fail if filename collides, could retry in a loop if that happens
-        symlink(&path, &tmp_symlink)?;
-        let key_path = self.key_path(&entry.key.cache_key());
-        std::fs::rename(&tmp_symlink, &key_path)?;
-        debug_msg!("store key {}", key_path.display());
+    /// Write the given key/value pair to the cache, persisting it for at least the given TTL.
+    fn store<K, V>(&self, key: &K, value: &V, ttl: Duration) -> Result<()>
+            where K: CacheKey+Serialize, V: Serialize {
+        self.store_at(key, value, ttl, None)
+    }
+
+    /// Like store(), but optionally backdates the entry to the given store time.
+    fn store_at<K, V>(&self, key: &K, value: &V, ttl: Duration, stored: Option<SystemTime>) -> Result<()>
+            where K: CacheKey+Serialize, V: Serialize {
+        assert!(!ttl.as_secs() > 0 || ttl.subsec_nanos() > 0, "ttl cannot be zero"); // TODO use is_zero once stable
+        self.ensure_writable()?;
+        for tag in &self.tags {
+            Cache::check_tag(tag)?;
+        }
+        let ttl_dir = self.data_dir().join(Cache::seconds_ceiling(self.capped_ttl(ttl)).to_string());
+        std::fs::create_dir_all(&ttl_dir)?;
+        std::fs::create_dir_all(self.key_dir())?;
+        let path = Cache::rand_filename(&ttl_dir, "data");
+        // Note: this will fail if filename collides, could retry in a loop if that happens
+        let file = OpenOptions::new().create_new(true).write(true).open(&path)?;
+        let entry = CacheEntry{ key, value };
+        Cache::serialize(BufWriter::new(&file), &entry).context("Serialization failed")?;
+        if let Some(stored) = stored {
+            file.set_modified(stored)?;
+        }
+        debug!("store data {}", path.display());
+        // Roundabout approach to an atomic symlink replacement
+        // https://github.com/dimo414/bash-cache/issues/26
+        let tmp_symlink = Cache::rand_filename(&self.key_dir(), "tmp-symlink");
+        // Note: this will fail if filename collides, could retry in a loop if that happens
+        symlink(&path, &tmp_symlink)?;
+        let key_file = self.key_file(&entry.key.cache_key());
+        // Tag the key before it becomes visible, so a concurrent invalidation can't miss it
+        for tag in &self.tags {
+            let tag_dir = self.tag_dir(tag)?;
+            std::fs::create_dir_all(&tag_dir)?;
+            File::create(tag_dir.join(&key_file))?;
+        }
+        // Markers are per key, so drop any left by a previous result cached with other tags
+        if let Ok(tags_dir_iter) = std::fs::read_dir(self.cache_dir.join("tags")) {
+            for entry in tags_dir_iter.flatten() {
+                if !self.tags.iter().any(|tag| OsStr::new(tag) == entry.file_name()) {
+                    let _ = std::fs::remove_file(entry.path().join(&key_file));
+                }
+            }
+        }
+        let key_path = self.key_dir().join(&key_file);
+        std::fs::rename(&tmp_symlink, &key_path)?;
+        debug!("store key {}", key_path.display());
+        Ok(())
+    }
+
+    /// Removes all keys, in any scope, that were stored with the given tag. Returns the number of
+    /// keys removed.
+    fn invalidate_tag(&self, tag: &str) -> Result<usize> {
+        self.ensure_writable()?;
+        let tag_dir = self.tag_dir(tag)?;
+        let dir_iter = match std::fs::read_dir(&tag_dir) {
+            Ok(dir_iter) => dir_iter,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(Error::new(e).context("Failed to access tag directory")),
+        };
+        let mut removed = 0;
+        for entry in dir_iter {
+            let marker = entry?;
+            // A key may have already expired or been concurrently removed
+            if std::fs::remove_file(self.key_dir().join(marker.file_name())).is_ok() {
+                debug!("invalidated {:?} tagged {}", marker.file_name(), tag);
+                removed += 1;
+            }
+            let _ = std::fs::remove_file(marker.path());
+        }
+        // Fails harmlessly if a concurrent store just tagged a new key
+        let _ = std::fs::remove_dir(&tag_dir);
+        Ok(removed)
+    }
+
+    /// Removes everything stored for keys in this cache's scope: results, statistics, watch
+    /// registrations, and per-key rate limit budgets. Returns the number of results removed.
+    fn clear(&self) -> Result<usize> {
+        self.ensure_writable()?;
+        fn in_scope_files(cache: &Cache, dir: &Path, prefix: &str) -> Result<Vec<PathBuf>> {
+            let dir_iter = match std::fs::read_dir(dir) {
+                Ok(dir_iter) => dir_iter,
+                Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+                Err(e) => return Err(Error::new(e).context(format!("Failed to access {}", dir.display()))),
+            };
+            let mut ret = vec![];
+            for entry in dir_iter {
+                let entry = entry?;
+                if let Some(name) = entry.file_name().to_str().and_then(|n| n.strip_prefix(prefix)) {
+                    if cache.in_scope(name) {
+                        ret.push(entry.path());
+                    }
+                }
+            }
+            Ok(ret)
+        }
+
+        let mut removed = 0;
+        // Files may be concurrently removed, e.g. by a cleanup
+        for key in in_scope_files(self, &self.key_dir(), "")? {
+            if std::fs::remove_file(key).is_ok() {
+                removed += 1;
+            }
+        }
+        for file in in_scope_files(self, &self.stats_dir(), "")?.into_iter()
+                .chain(in_scope_files(self, &self.watch_dir(), "")?) {
+            let _ = std::fs::remove_file(file);
+        }
+        for budget_dir in in_scope_files(self, &self.rate_dir(), "key.")? {
+            let _ = std::fs::remove_file(budget_dir.join("executions"));
+            let _ = std::fs::remove_dir(budget_dir);
+        }
+        debug!("cleared {} results in scope {:?}", removed, self.scope);
+        Ok(removed)
+    }
+
+    /// Parses the scope out of a key file name, or returns None if the file isn't a key.
+    fn parse_key_file(file_name: &str) -> Option<Option<String>> {
+        if file_name.starts_with("tmp-symlink.") {
+            return None;
+        }
+        Some(file_name.rsplit_once('.').map(|(scope, _)| scope.to_string()))
+    }
+
+    /// Returns the tags associated with each tagged key file.
+    fn key_tags(&self) -> Result<BTreeMap<OsString, Vec<String>>> {
+        let mut key_tags = BTreeMap::new();
+        if let Ok(tags_dir_iter) = std::fs::read_dir(self.cache_dir.join("tags")) {
+            for entry in tags_dir_iter {
+                let entry = entry?;
+                let tag = entry.file_name().to_string_lossy().to_string();
+                let markers = match std::fs::read_dir(entry.path()) {
+                    Ok(markers) => markers,
+                    Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e.into()),
+                };
+                for marker in markers {
+                    key_tags.entry(marker?.file_name()).or_insert_with(Vec::new).push(tag.clone());
+                }
+            }
+        }
+        Ok(key_tags)
+    }
+
+    /// Writes all unexpired entries to the given writer as a portable archive that can be loaded
+    /// by import(). If this cache is scoped only entries in its scope are exported, otherwise all
+    /// entries are. Returns the number of entries exported.
+    fn export<K, V, W>(&self, writer: W) -> Result<usize>
+            where K: Serialize+DeserializeOwned, V: Serialize+DeserializeOwned, W: io::Write {
+        let key_tags = self.key_tags()?;
+        let mut entries = vec![];
+        if let Ok(key_dir_iter) = std::fs::read_dir(self.key_dir()) {
+            for entry in key_dir_iter {
+                let entry = entry?;
+                let file_name = entry.file_name();
+                let scope = match file_name.to_str().and_then(Cache::parse_key_file) {
+                    Some(scope) => scope,
+                    None => continue,
+                };
+                if self.scope.is_some() && scope != self.scope {
+                    continue;
+                }
+                // Keys can expire or be replaced concurrently; skip any that can't be read
+                let data_path = match std::fs::read_link(entry.path()) {
+                    Ok(data_path) => data_path,
+                    Err(_) => continue,
+                };
+                let ttl = data_path.parent().and_then(|p| p.file_name())
+                    .and_then(|s| s.to_str()).and_then(|s| s.parse().ok()).map(Duration::from_secs);
+                let (file, ttl) = match (File::open(&data_path), ttl) {
+                    (Ok(file), Some(ttl)) => (file, ttl),
+                    _ => continue,
+                };
+                let stored = file.metadata()?.modified()?;
+                if stored.elapsed().map(|age| age > ttl).unwrap_or(true) {
+                    continue;
+                }
+                let found: CacheEntry<K, V> = match Cache::deserialize(BufReader::new(file)) {
+                    Ok(found) => found,
+                    Err(_) => continue,
+                };
+                entries.push(ArchivedEntry {
+                    key: found.key,
+                    value: found.value,
+                    scope,
+                    stored,
+                    ttl,
+                    tags: key_tags.get(&file_name).cloned().unwrap_or_default(),
+                });
+            }
+        }
+        let count = entries.len();
+        Cache::serialize(writer, &CacheArchive { created: SystemTime::now(), entries })
+            .context("Serialization failed")?;
+        debug!("exported {} entries", count);
+        Ok(count)
+    }
+
+    /// Stores the entries in an archive created by export(), preserving their scopes, tags, and
+    /// store times. If this cache is scoped only entries in its scope are imported. If `rebase` is
+    /// true store times are shifted forward by the archive's age, so entries are as fresh as they
+    /// were when exported. Entries that have expired are skipped. Returns the number of entries
+    /// imported.
+    fn import<K, V, R>(&self, reader: R, rebase: bool) -> Result<usize>
+            where K: CacheKey+Serialize+DeserializeOwned, V: Serialize+DeserializeOwned, R: io::Read {
+        self.ensure_writable()?;
+        let archive: CacheArchive<K, V> = Cache::deserialize(reader).context("Invalid cache archive")?;
+        let offset = if rebase { archive.created.elapsed().unwrap_or_default() } else { Duration::ZERO };
+        let mut count = 0;
+        for entry in archive.entries {
+            if self.scope.is_some() && entry.scope != self.scope {
+                continue;
+            }
+            // Clock skew or rebasing could otherwise place an entry in the future
+            let stored = std::cmp::min(entry.stored + offset, SystemTime::now());
+            if stored.elapsed().map(|age| age > entry.ttl).unwrap_or(false) {
+                continue;
+            }
+            let mut cache = Cache { scope: entry.scope, tags: vec![], ..self.clone() };
+            for tag in entry.tags {
+                cache = cache.tagged(tag);
+            }
+            cache.store_at(&entry.key, &entry.value, entry.ttl, Some(stored))?;
+            count += 1;
+        }
+        debug!("imported {} entries", count);
+        Ok(count)
+    }
+
+    fn rate_dir(&self) -> PathBuf {
+        self.cache_dir.join("rates")
+    }
+
+    fn watch_dir(&self) -> PathBuf {
+        self.cache_dir.join("watches")
+    }
+
+    /// Records the paths the given key depends on, unless the same entry is already recorded.
+    fn register_watch<K: CacheKey>(&self, key: &K, entry: &WatchEntry) -> Result<()> {
+        self.ensure_writable()?;
+        let path = self.watch_dir().join(self.key_file(&key.cache_key()));
+        let existing: Option<WatchEntry> = File::open(&path).ok()
+            .and_then(|file| Cache::deserialize(BufReader::new(file)).ok());
+        if existing.as_ref() == Some(entry) {
+            return Ok(());
+        }
+        std::fs::create_dir_all(self.watch_dir())?;
+        // Write to a temp file and rename it into place, so the entry is only visible once complete
+        let tmp_path = Cache::rand_filename(&self.watch_dir(), "tmp-watch");
+        Cache::serialize(BufWriter::new(File::create(&tmp_path)?), entry)?;
+        std::fs::rename(&tmp_path, &path)?;
+        debug!("registered watch {}", path.display());
+        Ok(())
+    }
+
+    /// Returns the watch entries of every key in this cache's scope (or all scopes, if unscoped),
+    /// along with their key file names.
+    #[cfg(target_os = "linux")]
+    fn watches(&self) -> Result<Vec<(OsString, WatchEntry)>> {
+        let mut watches = Vec::new();
+        if let Ok(watch_dir_iter) = std::fs::read_dir(self.watch_dir()) {
+            for entry in watch_dir_iter {
+                let entry = entry?;
+                let file_name = entry.file_name();
+                let in_scope = file_name.to_str()
+                    .map(|f| !f.starts_with("tmp-watch.") && (self.scope.is_none() || self.in_scope(f)))
+                    .unwrap_or(false);
+                if !in_scope {
+                    continue;
+                }
+                // Entries can be replaced concurrently; skip any that can't be read
+                let watch: Option<WatchEntry> = File::open(entry.path()).ok()
+                    .and_then(|file| Cache::deserialize(BufReader::new(file)).ok());
+                if let Some(watch) = watch {
+                    watches.push((file_name, watch));
+                }
+            }
+        }
+        Ok(watches)
+    }
+
+    /// Removes the given key, returning whether it existed.
+    #[cfg(target_os = "linux")]
+    fn remove_key_file(&self, key_file: &OsStr) -> Result<bool> {
+        self.ensure_writable()?;
+        match std::fs::remove_file(self.key_dir().join(key_file)) {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(Error::new(e).context("Failed to remove key")),
+        }
+    }
+
+    /// Records an execution against each of the named rate limit budgets, unless any of them has
+    /// been exhausted, in which case nothing is recorded and the time until that budget frees up
+    /// is returned.
+    fn consume_rate_budgets(&self, budgets: &[(String, RateLimit)]) -> Result<Option<Duration>> {
+        self.ensure_writable()?;
+        // Hold every budget's lock while checking them all, so nothing is consumed unless all
+        // budgets are available. Budgets are locked in a consistent order to avoid deadlocks.
+        let mut budgets: Vec<_> = budgets.iter().collect();
+        budgets.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut locked = Vec::new();
+        for (name, limit) in budgets {
+            let budget_dir = self.rate_dir().join(name);
+            std::fs::create_dir_all(&budget_dir)?;
+            let lock = FileLock::acquire(&budget_dir, "budget", Duration::from_secs(60), Duration::from_secs(5))
+                .with_context(|| format!("Failed to lock rate limit {}", name))?;
+            let path = budget_dir.join("executions");
+            let mut state: RateState = match File::open(&path) {
+                // Start over rather than failing forever if the state is somehow corrupt
+                Ok(file) => Cache::deserialize(BufReader::new(file)).unwrap_or_else(|e| {
+                    warn!("discarding unreadable rate limit {}: {:?}", name, e);
+                    RateState::default()
+                }),
+                Err(e) if e.kind() == ErrorKind::NotFound => RateState::default(),
+                Err(e) => return Err(Error::new(e).context("Failed to read rate limit")),
+            };
+            let now = SystemTime::now();
+            state.window = limit.per;
+            state.executions.retain(|t| now.duration_since(*t).map(|age| age < limit.per).unwrap_or(true));
+            if state.executions.len() >= limit.executions as usize {
+                let oldest = state.executions.iter().min().expect("Non-empty");
+                let retry_after = limit.per.saturating_sub(now.duration_since(*oldest).unwrap_or_default());
+                debug!("rate limit {} exhausted for {:?}", name, retry_after);
+                return Ok(Some(retry_after));
+            }
+            locked.push((budget_dir, path, state, lock));
+        }
+        for (budget_dir, path, mut state, _lock) in locked {
+            state.executions.push(SystemTime::now());
+            // Write to a temp file and rename it into place, so readers never see a partial write
+            let tmp_path = Cache::rand_filename(&budget_dir, "tmp-executions");
+            let file = OpenOptions::new().create_new(true).write(true).open(&tmp_path)?;
+            Cache::serialize(BufWriter::new(&file), &state).context("Failed to write rate limit")?;
+            std::fs::rename(&tmp_path, &path)?;
+        }
+        Ok(None)
+    }
+
+    /// Applies the given update to the usage statistics recorded for the given key. Updates are
+    /// best-effort; concurrent updates to the same key can race, in which case one is lost.
+    fn update_stats<K, F>(&self, key: &K, update: F) -> Result<()>
+            where K: CacheKey+Serialize+DeserializeOwned, F: FnOnce(&mut CommandStats) {
+        self.ensure_writable()?;
+        let path = self.stats_dir().join(self.key_file(&key.cache_key()));
+        let mut stats = match File::open(&path) {
+            Ok(file) => {
+                let found: CacheEntry<K, CommandStats> = Cache::deserialize(BufReader::new(file))
+                    .context("Failed to read stats file")?;
+                // Start over rather than conflating two keys that happened to collide
+                if &found.key == key { found.value } else { CommandStats::default() }
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => CommandStats::default(),
+            Err(e) => return Err(Error::new(e).context("Failed to access stats file")),
+        };
+        update(&mut stats);
+
+        std::fs::create_dir_all(self.stats_dir())?;
+        let tmp_path = Cache::rand_filename(&self.stats_dir(), "tmp-stats");
+        let file = OpenOptions::new().create_new(true).write(true).open(&tmp_path)?;
+        Cache::serialize(BufWriter::new(&file), &CacheEntry{ key, value: &stats })
+            .context("Serialization failed")?;
+        std::fs::rename(&tmp_path, &path)?;
+        trace!("stats updated {}", path.display());
         Ok(())
     }
 
-    fn cleanup(&self) -> Result<()> {
-        fn delete_stale_file(file: &Path, ttl: Duration) -> Result<()> {
+    /// Returns the usage statistics recorded for all keys in this cache's scope.
+    fn stats<K>(&self) -> Result<Vec<(K, CommandStats)>> where K: DeserializeOwned {
+        let dir_iter = match std::fs::read_dir(self.stats_dir()) {
+            Ok(dir_iter) => dir_iter,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(Error::new(e).context("Failed to access stats directory")),
+        };
+        let mut ret = vec![];
+        for entry in dir_iter {
+            let entry = entry?;
+            match entry.file_name().to_str() {
+                Some(name) if self.in_scope(name) => {},
+                _ => continue,
+            }
+            // Files may be concurrently replaced; skip any that can't be read
+            if let Ok(file) = File::open(entry.path()) {
+                if let Ok(found) = Cache::deserialize::<_, CacheEntry<K, CommandStats>>(BufReader::new(file)) {
+                    ret.push((found.key, found.value));
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Removes stale data from the cache, returning what was removed, or None if the cleanup was
+    /// skipped because another cleanup is in progress or one ran within the policy's throttle.
+    fn cleanup(&self, policy: &CleanupPolicy) -> Result<Option<CleanupReport>> {
+        if self.read_only {
+            debug!("cleanup skip read-only");
+            return Ok(None);
+        }
+        fn delete_stale_file(file: &Path, ttl: Duration, report: &mut CleanupReport) -> Result<()> {
             let age = std::fs::metadata(file)?.modified()?.elapsed()?;
             if age > ttl {
-                std::fs::remove_file(&file)?;
+                report.record(file);
             }
             Ok(())
         }
 
+        let mut report = CleanupReport::default();
         // if try_acquire fails, e.g. because the directory does not exist, there's nothing to clean up
-        if let Ok(Some(_lock)) = FileLock::try_acquire(&self.cache_dir, "cleanup", Duration::from_secs(60*10)) {
+        if let Ok(Some(_lock)) = FileLock::try_acquire(&self.cache_dir, "cleanup", policy.stale_lock) {
             // Don't bother if cleanup has been attempted recently
             let last_attempt_file = self.cache_dir.join("last_cleanup");
             if let Ok(metadata) = last_attempt_file.metadata() {
-                if metadata.modified()?.elapsed()? < Duration::from_secs(30) {
-                    debug_msg!("cleanup skip recent");
-                    return Ok(());
+                if metadata.modified()?.elapsed()? < policy.throttle {
+                    debug!("cleanup skip recent");
+                    return Ok(None);
                 }
             }
             File::create(&last_attempt_file)?; // resets mtime if already exists
+            debug!("cleanup starting {}", self.cache_dir.display());
 
             // First delete stale data files
-            debug_msg!("cleanup data {}", &self.data_dir().display());
-            if let Ok(data_dir_iter) = std::fs::read_dir(&self.data_dir()) {
+            debug!("cleanup data {}", &self.data_dir().display());
+            if let Ok(data_dir_iter) = std::fs::read_dir(self.data_dir()) {
                 for entry in data_dir_iter {
                     let ttl_dir = entry?.path();
                     let ttl = Duration::from_secs(
@@ -542,14 +2569,14 @@ impl Cache {
                         let file = entry?.path();
                         // Disregard errors on individual files; typically due to concurrent deletion
                         // or other changes we don't care about.
-                        let _ = delete_stale_file(&file, ttl);
+                        let _ = delete_stale_file(&file, ttl, &mut report);
                     }
                 }
             }
 
             // Then delete broken symlinks
-            debug_msg!("cleanup keys {}", &self.key_dir().display());
-            if let Ok(key_dir_iter) = std::fs::read_dir(&self.key_dir()) {
+            debug!("cleanup keys {}", &self.key_dir().display());
+            if let Ok(key_dir_iter) = std::fs::read_dir(self.key_dir()) {
                 for entry in key_dir_iter {
                     let symlink = entry?.path();
                     // This reads as if we're deleting files that no longer exist, but what it really
@@ -559,16 +2586,96 @@ impl Cache {
                     // std::fs::symlink_metadata() could be used to check that the symlink itself exists
                     // if needed, but this could still have false-positives due to a TOCTOU race.
                     if !symlink.exists() {
-                        let _ = std::fs::remove_file(symlink);
+                        report.record(&symlink);
+                    }
+                }
+            }
+
+            // Finally delete tag markers whose keys are gone
+            debug!("cleanup tags {}", self.cache_dir.join("tags").display());
+            if let Ok(tags_dir_iter) = std::fs::read_dir(self.cache_dir.join("tags")) {
+                for entry in tags_dir_iter {
+                    let tag_dir = entry?.path();
+                    // The tag may have been invalidated concurrently
+                    let markers = match std::fs::read_dir(&tag_dir) {
+                        Ok(markers) => markers,
+                        Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                        Err(e) => return Err(e.into()),
+                    };
+                    for entry in markers {
+                        let marker = entry?;
+                        // Check the key symlink itself, not its target
+                        if std::fs::symlink_metadata(self.key_dir().join(marker.file_name())).is_err() {
+                            report.record(&marker.path());
+                        }
+                    }
+                    let _ = std::fs::remove_dir(&tag_dir); // only succeeds if empty
+                }
+            }
+
+            // And watch entries whose keys are gone
+            debug!("cleanup watches {}", self.watch_dir().display());
+            if let Ok(watch_dir_iter) = std::fs::read_dir(self.watch_dir()) {
+                for entry in watch_dir_iter {
+                    let entry = entry?;
+                    // Leave registrations being written alone, unless they were abandoned
+                    let in_flight = entry.file_name().to_string_lossy().starts_with("tmp-watch.")
+                        && entry.metadata().and_then(|m| m.modified()).ok()
+                            .and_then(|mtime| mtime.elapsed().ok())
+                            .map(|age| age < Duration::from_secs(60)).unwrap_or(true);
+                    if !in_flight && std::fs::symlink_metadata(self.key_dir().join(entry.file_name())).is_err() {
+                        report.record(&entry.path());
+                    }
+                }
+            }
+
+            // And statistics that haven't been updated recently and whose keys are gone
+            debug!("cleanup stats {}", self.stats_dir().display());
+            if let Ok(stats_dir_iter) = std::fs::read_dir(self.stats_dir()) {
+                for entry in stats_dir_iter {
+                    let entry = entry?;
+                    let age = entry.metadata().and_then(|m| m.modified()).ok()
+                        .and_then(|mtime| mtime.elapsed().ok());
+                    // Updates being written are only left alone until they're clearly abandoned
+                    let retention = if entry.file_name().to_string_lossy().starts_with("tmp-stats.") {
+                        Duration::from_secs(60)
+                    } else {
+                        policy.stats_retention
+                    };
+                    if age.map(|age| age > retention).unwrap_or(false)
+                            && std::fs::symlink_metadata(self.key_dir().join(entry.file_name())).is_err() {
+                        report.record(&entry.path());
+                    }
+                }
+            }
+
+            // And rate limit budgets that have no recent executions
+            debug!("cleanup rates {}", self.rate_dir().display());
+            if let Ok(rate_dir_iter) = std::fs::read_dir(self.rate_dir()) {
+                for entry in rate_dir_iter {
+                    let budget_dir = entry?.path();
+                    let path = budget_dir.join("executions");
+                    // Unreadable state is discarded by the next execution anyway
+                    let state: Option<Option<RateState>> = File::open(&path).ok()
+                        .map(|file| Cache::deserialize(BufReader::new(file)).ok());
+                    let unused = state.map(|state| state.map(|state| state.executions.iter()
+                        .all(|t| t.elapsed().map(|age| age > state.window).unwrap_or(false))).unwrap_or(true));
+                    if unused.unwrap_or(false) {
+                        report.record(&path);
+                        let _ = std::fs::remove_dir(&budget_dir); // only succeeds if empty
                     }
                 }
             }
+            debug!("cleanup removed {} files ({} bytes)", report.files, report.bytes);
+            return Ok(Some(report));
         }
-        Ok(())
+        debug!("cleanup skip locked");
+        Ok(None)
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::needless_borrows_for_generic_args)]
 mod cache_tests {
     use super::*;
     use test_dir::{TestDir, DirBuilder};
@@ -584,119 +2691,559 @@ mod cache_tests {
         std::fs::metadata(&path).expect("No metadata").modified().expect("No modtime")
     }
 
-    fn make_dir_stale<P: AsRef<Path>>(dir: P, age: Duration) -> Result<()> {
-        let desired_time = SystemTime::now() - age;
-        let stale_time = filetime::FileTime::from_system_time(desired_time);
-        for entry in std::fs::read_dir(dir)? {
-            let path = entry?.path();
-            let last_modified = modtime(&path);
-
-            if path.is_file() && last_modified > desired_time {
-                filetime::set_file_mtime(&path, stale_time)?;
-            } else if path.is_dir() {
-                make_dir_stale(&path, age)?;
-            }
-        }
+    fn make_dir_stale<P: AsRef<Path>>(dir: P, age: Duration) -> Result<()> {
+        let desired_time = SystemTime::now() - age;
+        let stale_time = filetime::FileTime::from_system_time(desired_time);
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let last_modified = modtime(&path);
+
+            if path.is_file() && last_modified > desired_time {
+                filetime::set_file_mtime(&path, stale_time)?;
+            } else if path.is_dir() {
+                make_dir_stale(&path, age)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn dir_contents<P: AsRef<Path>>(dir: P) -> Vec<String> {
+        fn contents(dir: &Path, ret: &mut Vec<PathBuf>) -> Result<()> {
+            for entry in std::fs::read_dir(&dir)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    contents(&path, ret)?;
+                } else {
+                    ret.push(path);
+                }
+            }
+            Ok(())
+        }
+        let mut paths = vec![];
+        contents(dir.as_ref(), &mut paths).unwrap();
+        paths.iter().map(|p| p.strip_prefix(dir.as_ref()).unwrap().display().to_string()).collect()
+    }
+
+    // Sanity-checking that cache_key's behavior is stable over time. This test may need to be
+    // updated when changing Rust versions / editions.
+    #[test]
+    fn stable_hash() {
+        assert_eq!(100.cache_key(), "7D208C81E8236995");
+        if cfg!(feature = "debug") {
+            assert_eq!("FooBar".to_string().cache_key(), "FooBar_2C8878C07E3ADA57");
+        } else {
+            assert_eq!("FooBar".to_string().cache_key(), "2C8878C07E3ADA57");
+        }
+    }
+
+    #[test]
+    fn cache() {
+        let dir = TestDir::temp();
+        let key = "foo".to_string();
+        let val = "A".to_string();
+        let cache = Cache::new(&dir.root());
+
+        let absent = cache.lookup::<_, String>(&key, Duration::from_secs(100)).unwrap();
+        assert!(absent.is_none());
+
+        cache.store(&key, &val, Duration::from_secs(100)).unwrap();
+        let present = cache.lookup::<_, String>(&key, Duration::from_secs(100)).unwrap();
+        assert_eq!(present.unwrap().0, "A");
+    }
+
+    #[test]
+    fn lookup_ttls() {
+        let dir = TestDir::temp();
+        let key = "foo".to_string();
+        let val = "A".to_string();
+        let cache = Cache::new(&dir.root());
+
+        cache.store(&key, &val, Duration::from_secs(5)).unwrap(); // store duration doesn't affect lookups
+        make_dir_stale(dir.root(), Duration::from_secs(15)).unwrap();
+
+        // data is still present until a cleanup iteration runs, or a lookup() invalidates it
+        let present = cache.lookup::<_, String>(&key, Duration::from_secs(20)).unwrap();
+        assert_eq!(present.unwrap().0, "A");
+        // lookup() finds stale data, deletes it
+        let absent = cache.lookup::<_, String>(&key, Duration::from_secs(10)).unwrap();
+        assert!(absent.is_none());
+        // now data is gone, even though this lookup() would have accepted it
+        let absent = cache.lookup::<_, String>(&key, Duration::from_secs(20)).unwrap();
+        assert!(absent.is_none());
+    }
+
+    #[test]
+    fn read_only() {
+        let dir = TestDir::temp();
+        let key = "foo".to_string();
+        let val = "A".to_string();
+        let cache = Cache::new(dir.root());
+        let ro_cache = Cache::new(dir.root()).read_only(true);
+
+        assert!(ro_cache.store(&key, &val, Duration::from_secs(5)).is_err());
+        cache.store(&key, &val, Duration::from_secs(5)).unwrap();
+        make_dir_stale(dir.root(), Duration::from_secs(15)).unwrap();
+
+        // expired data is not deleted
+        let absent = ro_cache.lookup::<_, String>(&key, Duration::from_secs(10)).unwrap();
+        assert!(absent.is_none());
+        let present = ro_cache.lookup::<_, String>(&key, Duration::from_secs(20)).unwrap();
+        assert_eq!(present.unwrap().0, "A");
+
+        assert_eq!(ro_cache.cleanup(&CleanupPolicy::default()).unwrap(), None);
+        assert!(!dir.path("last_cleanup").exists());
+        assert!(ro_cache.invalidate_tag("tag").is_err());
+        assert!(ro_cache.secret_salt().is_ok());
+        assert!(!dir.path("secret_salt").exists());
+    }
+
+    #[test]
+    fn max_ttl() {
+        let dir = TestDir::temp();
+        let key = "foo".to_string();
+        let val = "A".to_string();
+        let cache = Cache::new(dir.root()).max_ttl(Some(Duration::from_secs(10)));
+
+        cache.store(&key, &val, Duration::from_secs(100)).unwrap();
+        let present = cache.lookup::<_, String>(&key, Duration::from_secs(20)).unwrap();
+        assert_eq!(present.unwrap().0, "A");
+        make_dir_stale(dir.root(), Duration::from_secs(15)).unwrap();
+        let absent = cache.lookup::<_, String>(&key, Duration::from_secs(20)).unwrap();
+        assert!(absent.is_none());
+    }
+
+    #[test]
+    fn scoped() {
+        let dir = TestDir::temp();
+        let key = "foo".to_string();
+        let val_a = "A".to_string();
+        let val_b = "B".to_string();
+        let cache = Cache::new(&dir.root());
+        let cache_scoped = Cache::new(&dir.root()).scoped("scope".into());
+
+        cache.store(&key, &val_a, Duration::from_secs(100)).unwrap();
+        cache_scoped.store(&key, &val_b, Duration::from_secs(100)).unwrap();
+
+        let present = cache.lookup::<_, String>(&key, Duration::from_secs(20)).unwrap();
+        assert_eq!(present.unwrap().0, "A");
+        let present_scoped = cache_scoped.lookup::<_, String>(&key, Duration::from_secs(20)).unwrap();
+        assert_eq!(present_scoped.unwrap().0, "B");
+    }
+
+    #[test]
+    fn cleanup() {
+        let dir = TestDir::temp();
+        let key = "foo".to_string();
+        let val = "A".to_string();
+        let cache = Cache::new(&dir.root());
+
+        cache.store(&key, &val, Duration::from_secs(5)).unwrap();
+        let key_file = std::fs::read_dir(dir.path("keys")).unwrap().next().unwrap().unwrap().path();
+        let data_size = std::fs::metadata(key_file).unwrap().len();
+        make_dir_stale(dir.root(), Duration::from_secs(10)).unwrap();
+        let report = cache.cleanup(&CleanupPolicy::default()).unwrap().expect("Not skipped");
+        assert_eq!(report.files_removed(), 2); // the data file and its key symlink
+        assert_eq!(report.bytes_reclaimed(), data_size); // the symlink doesn't count
+
+        assert_eq!(dir_contents(dir.root()), ["last_cleanup"]); // keys and data dirs are now empty
+
+        let absent = cache.lookup::<_, String>(&key, Duration::from_secs(20)).unwrap();
+        assert!(absent.is_none());
+
+        // throttled unless the policy allows back-to-back cleanups
+        assert_eq!(cache.cleanup(&CleanupPolicy::default()).unwrap(), None);
+        let unthrottled = CleanupPolicy::default().throttle(Duration::ZERO);
+        assert_eq!(cache.cleanup(&unthrottled).unwrap(), Some(CleanupReport::default()));
+    }
+
+    #[test]
+    fn rate_budgets() {
+        let dir = TestDir::temp();
+        let cache = Cache::new(dir.root());
+        let limit = RateLimit::new(2, Duration::from_secs(60));
+        let budgets = [("a".to_string(), limit), ("b".to_string(), limit)];
+
+        assert_eq!(cache.consume_rate_budgets(&budgets[..1]).unwrap(), None);
+        assert_eq!(cache.consume_rate_budgets(&budgets).unwrap(), None);
+        assert!(cache.consume_rate_budgets(&budgets).unwrap().is_some());
+        // b's budget wasn't consumed since a's was exhausted
+        assert_eq!(cache.consume_rate_budgets(&budgets[1..]).unwrap(), None);
+        assert!(cache.consume_rate_budgets(&budgets[1..]).unwrap().is_some());
+
+        // budgets with no executions in their window are cleaned up
+        let short = [("c".to_string(), RateLimit::new(1, Duration::from_millis(10)))];
+        assert_eq!(cache.consume_rate_budgets(&short).unwrap(), None);
+        std::thread::sleep(Duration::from_millis(20));
+        cache.cleanup(&CleanupPolicy::default()).unwrap();
+        assert!(dir.path("rates/a").exists());
+        assert!(!dir.path("rates/c").exists());
+    }
+
+    #[test]
+    fn tags() {
+        let dir = TestDir::temp();
+        let cache = Cache::new(dir.root());
+        let tagged = Cache::new(dir.root()).tagged("a".into());
+        let tagged_scoped = Cache::new(dir.root()).scoped("scope".into()).tagged("a".into()).tagged("b".into());
+
+        cache.store(&"foo".to_string(), &"A".to_string(), Duration::from_secs(100)).unwrap();
+        tagged.store(&"bar".to_string(), &"B".to_string(), Duration::from_secs(100)).unwrap();
+        tagged_scoped.store(&"bar".to_string(), &"C".to_string(), Duration::from_secs(100)).unwrap();
+
+        assert_eq!(cache.invalidate_tag("c").unwrap(), 0);
+        assert_eq!(cache.invalidate_tag("a").unwrap(), 2);
+        assert!(cache.lookup::<_, String>(&"foo".to_string(), Duration::from_secs(100)).unwrap().is_some());
+        assert!(tagged.lookup::<_, String>(&"bar".to_string(), Duration::from_secs(100)).unwrap().is_none());
+        assert!(tagged_scoped.lookup::<_, String>(&"bar".to_string(), Duration::from_secs(100)).unwrap().is_none());
+        assert_eq!(cache.invalidate_tag("a").unwrap(), 0);
+
+        // the "b" marker is left behind until cleanup
+        assert!(dir.path("tags/b").exists());
+        cache.cleanup(&CleanupPolicy::default()).unwrap();
+        assert!(!dir.path("tags/b").exists());
+
+        // re-caching a key without a tag removes its marker
+        tagged.store(&"bar".to_string(), &"D".to_string(), Duration::from_secs(100)).unwrap();
+        cache.store(&"bar".to_string(), &"E".to_string(), Duration::from_secs(100)).unwrap();
+        assert_eq!(cache.invalidate_tag("a").unwrap(), 0);
+
+        for invalid in ["", ".", "..", "../keys", "a/b"] {
+            assert!(cache.invalidate_tag(invalid).is_err(), "{}", invalid);
+            let tagged = Cache::new(dir.root()).tagged(invalid.into());
+            assert!(tagged.store(&"baz".to_string(), &"F".to_string(), Duration::from_secs(100)).is_err(), "{}", invalid);
+        }
+        assert!(dir.path("keys").exists());
+    }
+
+    #[test]
+    fn clear() {
+        let dir = TestDir::temp();
+        let cache = Cache::new(dir.root());
+        let scoped = Cache::new(dir.root()).scoped("scope".into());
+        let other = Cache::new(dir.root()).scoped("other".into());
+        let key = "foo".to_string();
+        let limit = [(format!("key.{}", scoped.key_file(&key.cache_key())), RateLimit::new(1, Duration::from_secs(60)))];
+        for cache in [&cache, &scoped, &other] {
+            cache.store(&key, &"A".to_string(), Duration::from_secs(100)).unwrap();
+            cache.update_stats(&key, |s| s.hits += 1).unwrap();
+        }
+        scoped.consume_rate_budgets(&limit).unwrap();
+
+        assert_eq!(scoped.clear().unwrap(), 1);
+        assert!(scoped.lookup::<_, String>(&key, Duration::from_secs(100)).unwrap().is_none());
+        assert!(scoped.stats::<String>().unwrap().is_empty());
+        assert_eq!(scoped.consume_rate_budgets(&limit).unwrap(), None);
+        // other scopes are unaffected
+        for cache in [&cache, &other] {
+            assert!(cache.lookup::<_, String>(&key, Duration::from_secs(100)).unwrap().is_some());
+            assert_eq!(cache.stats::<String>().unwrap().len(), 1);
+        }
+        assert_eq!(scoped.clear().unwrap(), 0);
+    }
+
+    #[test]
+    fn cleanup_stats() {
+        let dir = TestDir::temp();
+        let cache = Cache::new(dir.root());
+        let (cached, uncached) = ("foo".to_string(), "bar".to_string());
+        cache.store(&cached, &"A".to_string(), Duration::from_secs(100)).unwrap();
+        cache.update_stats(&cached, |s| s.hits += 1).unwrap();
+        cache.update_stats(&uncached, |s| s.misses += 1).unwrap();
+        // an abandoned update
+        File::create(cache.stats_dir().join("tmp-stats.abc")).unwrap();
+
+        let policy = CleanupPolicy::default().throttle(Duration::ZERO).stats_retention(Duration::from_secs(3600));
+        cache.cleanup(&policy).unwrap();
+        assert_eq!(dir_contents(cache.stats_dir()).len(), 3);
+
+        make_dir_stale(cache.stats_dir(), Duration::from_secs(7200)).unwrap();
+        cache.cleanup(&policy).unwrap();
+        // stats for keys that are still cached are kept regardless of age
+        assert_eq!(cache.stats::<String>().unwrap(), [(cached, CommandStats { hits: 1, ..CommandStats::default() })]);
+        assert_eq!(dir_contents(cache.stats_dir()).len(), 1);
+    }
+
+    #[test]
+    fn cleanup_watches() {
+        let dir = TestDir::temp();
+        let cache = Cache::new(dir.root());
+        let cmd = CommandDesc::new(["true"]);
+        let entry = WatchEntry {
+            command: cmd.clone(), cwd: dir.root().into(), paths: vec![dir.path("file")], ttl: Duration::from_secs(100),
+            scope: None, tags: vec![], on_change: OnChange::Invalidate,
+        };
+        cache.store(&cmd, &"A".to_string(), Duration::from_secs(100)).unwrap();
+        cache.register_watch(&cmd, &entry).unwrap();
+        let watch_file = cache.watch_dir().join(cache.key_file(&cmd.cache_key()));
+        // a registration still being written
+        let tmp_file = cache.watch_dir().join("tmp-watch.abc");
+        File::create(&tmp_file).unwrap();
+
+        let policy = CleanupPolicy::default().throttle(Duration::ZERO);
+        cache.cleanup(&policy).unwrap();
+        assert!(watch_file.exists() && tmp_file.exists());
+        std::fs::remove_file(cache.key_path(&cmd.cache_key())).unwrap();
+        cache.cleanup(&policy).unwrap();
+        assert!(!watch_file.exists() && tmp_file.exists());
+    }
+
+    #[test]
+    fn export_import() {
+        let dir = TestDir::temp();
+        let src = Cache::new(dir.path("src"));
+        let src_scoped = Cache::new(dir.path("src")).scoped("scope".into()).tagged("tag".into());
+        src.store(&"foo".to_string(), &"A".to_string(), Duration::from_secs(100)).unwrap();
+        src.store(&"bar".to_string(), &"B".to_string(), Duration::from_secs(5)).unwrap();
+        src_scoped.store(&"foo".to_string(), &"C".to_string(), Duration::from_secs(100)).unwrap();
+        make_dir_stale(dir.path("src"), Duration::from_secs(10)).unwrap(); // expires bar
+
+        let mut archive = vec![];
+        assert_eq!(src.export::<String, String, _>(&mut archive).unwrap(), 2);
+        let mut scoped_archive = vec![];
+        assert_eq!(src_scoped.export::<String, String, _>(&mut scoped_archive).unwrap(), 1);
+
+        let dest = Cache::new(dir.path("dest"));
+        let dest_scoped = Cache::new(dir.path("dest")).scoped("scope".into());
+        assert_eq!(dest_scoped.import::<String, String, _>(&archive[..], false).unwrap(), 1);
+        assert!(dest.lookup::<_, String>(&"foo".to_string(), Duration::from_secs(100)).unwrap().is_none());
+        assert_eq!(dest.import::<String, String, _>(&archive[..], false).unwrap(), 2);
+
+        let (found, stored) = dest.lookup::<_, String>(&"foo".to_string(), Duration::from_secs(100)).unwrap().unwrap();
+        assert_eq!(found, "A");
+        assert!(stored.elapsed().unwrap() >= Duration::from_secs(10), "store time not preserved");
+        let (found, _) = dest_scoped.lookup::<_, String>(&"foo".to_string(), Duration::from_secs(100)).unwrap().unwrap();
+        assert_eq!(found, "C");
+        assert_eq!(dest.invalidate_tag("tag").unwrap(), 1); // tags are preserved
+
+        // rebasing shifts store times forward
+        let rebased = Cache::new(dir.path("rebased"));
+        let mut archive: CacheArchive<String, String> = Cache::deserialize(&archive[..]).unwrap();
+        archive.created -= Duration::from_secs(60);
+        for entry in archive.entries.iter_mut() {
+            entry.stored -= Duration::from_secs(60);
+        }
+        let mut aged_archive = vec![];
+        Cache::serialize(&mut aged_archive, &archive).unwrap();
+        assert_eq!(rebased.import::<String, String, _>(&aged_archive[..], true).unwrap(), 2);
+        let (_, stored) = rebased.lookup::<_, String>(&"foo".to_string(), Duration::from_secs(100)).unwrap().unwrap();
+        let age = stored.elapsed().unwrap();
+        assert!(age >= Duration::from_secs(10) && age < Duration::from_secs(60), "store time not rebased: {:?}", age);
+    }
+
+    #[test]
+    fn stats() {
+        let dir = TestDir::temp();
+        let cache = Cache::new(dir.root());
+        let cache_scoped = Cache::new(dir.root()).scoped("scope".into());
+
+        assert!(cache.stats::<String>().unwrap().is_empty());
+        cache.update_stats(&"foo".to_string(), |s| s.hits += 1).unwrap();
+        cache.update_stats(&"foo".to_string(), |s| s.hits += 1).unwrap();
+        cache.update_stats(&"bar".to_string(), |s| s.misses += 1).unwrap();
+        cache_scoped.update_stats(&"foo".to_string(), |s| s.refreshes += 1).unwrap();
+
+        let mut stats = cache.stats::<String>().unwrap();
+        stats.sort();
+        assert_eq!(stats, [
+            ("bar".to_string(), CommandStats { misses: 1, ..CommandStats::default() }),
+            ("foo".to_string(), CommandStats { hits: 2, ..CommandStats::default() }),
+        ]);
+        assert_eq!(cache_scoped.stats::<String>().unwrap(), [
+            ("foo".to_string(), CommandStats { refreshes: 1, ..CommandStats::default() }),
+        ]);
+    }
+}
+
+/// A cache backed by an HTTP server that stores opaque blobs, such as a Bazel-style remote cache
+/// or a WebDAV-enabled directory. Entries are read with `GET <base_url>/<key>` and written with
+/// `PUT <base_url>/<key>`, where `<key>` is the entry's key file name in the given local `Cache`.
+/// A 404 response is treated as a cache miss, while other failures are returned as errors.
+#[cfg(feature = "http")]
+#[derive(Clone, Debug)]
+struct RemoteCache {
+    base_url: String,
+    agent: ureq::Agent,
+}
+
+#[cfg(feature = "http")]
+impl RemoteCache {
+    fn new(base_url: &str, timeout: Duration) -> Self {
+        let agent = ureq::AgentBuilder::new().timeout(timeout).build();
+        RemoteCache { base_url: base_url.trim_end_matches('/').into(), agent }
+    }
+
+    fn url(&self, cache: &Cache, key: &str) -> String {
+        // Scopes are arbitrary strings, so the key file must be escaped to form a single path segment
+        let segment: String = cache.key_file(key).bytes().map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        }).collect();
+        format!("{}/{}", self.base_url, segment)
+    }
+
+    fn lookup<K, V>(&self, cache: &Cache, key: &K, max_age: Duration) -> Result<Option<(V, SystemTime)>>
+            where K: CacheKey+DeserializeOwned, V: DeserializeOwned {
+        let url = self.url(cache, &key.cache_key());
+        let response = match self.agent.get(&url).call() {
+            Ok(response) => response,
+            Err(ureq::Error::Status(404, _)) => {
+                debug!("remote lookup {} not found", url);
+                return Ok(None);
+            },
+            Err(e) => return Err(Error::new(e).context("Remote cache request failed")),
+        };
+        let found: ArchivedEntry<K, V> = Cache::deserialize(BufReader::new(response.into_reader()))
+            .context("Invalid remote cache entry")?;
+        if found.stored.elapsed().map(|age| age > cache.capped_ttl(max_age)).unwrap_or(true) {
+            debug!("remote lookup {} expired", url);
+            return Ok(None);
+        }
+        // Ignore false-positive hits that happened to collide with the hash code
+        if &found.key != key {
+            debug!("remote lookup {} hash collision", url);
+            return Ok(None);
+        }
+        debug!("remote lookup {} found", url);
+        Ok(Some((found.value, found.stored)))
+    }
+
+    fn store<K, V>(&self, cache: &Cache, key: &K, value: &V, ttl: Duration) -> Result<()>
+            where K: CacheKey+Serialize, V: Serialize {
+        cache.ensure_writable()?;
+        let url = self.url(cache, &key.cache_key());
+        let entry = ArchivedEntry {
+            key, value, scope: cache.scope.clone(), stored: SystemTime::now(), ttl, tags: cache.tags.clone() };
+        let mut body = Vec::new();
+        Cache::serialize(&mut body, &entry).context("Serialization failed")?;
+        self.agent.put(&url).send_bytes(&body).context("Remote cache request failed")?;
+        debug!("remote store {}", url);
         Ok(())
     }
+}
 
-    fn dir_contents<P: AsRef<Path>>(dir: P) -> Vec<String> {
-        fn contents(dir: &Path, ret: &mut Vec<PathBuf>) -> Result<()> {
-            for entry in std::fs::read_dir(&dir)? {
-                let path = entry?.path();
-                if path.is_dir() {
-                    contents(&path, ret)?;
-                } else {
-                    ret.push(path);
+#[cfg(all(test, feature = "http"))]
+mod remote_cache_tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::io::{BufRead, Read};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use test_dir::{TestDir, DirBuilder};
+
+    /// Starts a minimal HTTP server in the background that stores PUT bodies in memory and serves
+    /// them back on GET, returning its base URL.
+    pub(crate) fn serve() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/cache", listener.local_addr().unwrap());
+        let blobs: Arc<Mutex<HashMap<String, Vec<u8>>>> = Arc::default();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut reader = BufReader::new(stream.unwrap());
+                let mut request = String::new();
+                reader.read_line(&mut request).unwrap();
+                let mut content_length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() { break; }
+                    if let Some((name, value)) = header.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            content_length = value.trim().parse().unwrap();
+                        }
+                    }
                 }
+                let mut parts = request.split_whitespace();
+                let (method, path) = (parts.next().unwrap(), parts.next().unwrap().to_string());
+                let (status, body) = match method {
+                    "PUT" => {
+                        let mut body = vec![0; content_length];
+                        reader.read_exact(&mut body).unwrap();
+                        blobs.lock().unwrap().insert(path, body);
+                        ("201 Created", vec![])
+                    },
+                    "GET" => match blobs.lock().unwrap().get(&path) {
+                        Some(body) => ("200 OK", body.clone()),
+                        None => ("404 Not Found", vec![]),
+                    },
+                    _ => ("405 Method Not Allowed", vec![]),
+                };
+                let mut stream = reader.into_inner();
+                write!(stream, "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                       status, body.len()).unwrap();
+                stream.write_all(&body).unwrap();
             }
-            Ok(())
-        }
-        let mut paths = vec![];
-        contents(dir.as_ref(), &mut paths).unwrap();
-        paths.iter().map(|p| p.strip_prefix(dir.as_ref()).unwrap().display().to_string()).collect()
-    }
-
-    // Sanity-checking that cache_key's behavior is stable over time. This test may need to be
-    // updated when changing Rust versions / editions.
-    #[test]
-    fn stable_hash() {
-        assert_eq!(100.cache_key(), "7D208C81E8236995");
-        if cfg!(feature = "debug") {
-            assert_eq!("FooBar".to_string().cache_key(), "FooBar_2C8878C07E3ADA57");
-        } else {
-            assert_eq!("FooBar".to_string().cache_key(), "2C8878C07E3ADA57");
-        }
+        });
+        url
     }
 
     #[test]
-    fn cache() {
+    fn store_and_lookup() {
         let dir = TestDir::temp();
+        let cache = Cache::new(dir.root());
+        let remote = RemoteCache::new(&serve(), Duration::from_secs(10));
         let key = "foo".to_string();
-        let val = "A".to_string();
-        let cache = Cache::new(&dir.root());
 
-        let absent = cache.lookup::<_, String>(&key, Duration::from_secs(100)).unwrap();
+        let absent = remote.lookup::<_, String>(&cache, &key, Duration::from_secs(10)).unwrap();
         assert!(absent.is_none());
-
-        cache.store(&key, &val, Duration::from_secs(100)).unwrap();
-        let present = cache.lookup::<_, String>(&key, Duration::from_secs(100)).unwrap();
+        remote.store(&cache, &key, &"A".to_string(), Duration::from_secs(10)).unwrap();
+        let present = remote.lookup::<_, String>(&cache, &key, Duration::from_secs(10)).unwrap();
         assert_eq!(present.unwrap().0, "A");
+
+        // scopes are kept separate
+        let scoped = Cache::new(dir.root()).scoped("scope".into());
+        let absent = remote.lookup::<_, String>(&scoped, &key, Duration::from_secs(10)).unwrap();
+        assert!(absent.is_none());
     }
 
     #[test]
-    fn lookup_ttls() {
+    fn expired() {
         let dir = TestDir::temp();
+        let cache = Cache::new(dir.root());
+        let remote = RemoteCache::new(&serve(), Duration::from_secs(10));
         let key = "foo".to_string();
-        let val = "A".to_string();
-        let cache = Cache::new(&dir.root());
-
-        cache.store(&key, &val, Duration::from_secs(5)).unwrap(); // store duration doesn't affect lookups
-        make_dir_stale(dir.root(), Duration::from_secs(15)).unwrap();
 
-        // data is still present until a cleanup iteration runs, or a lookup() invalidates it
-        let present = cache.lookup::<_, String>(&key, Duration::from_secs(20)).unwrap();
-        assert_eq!(present.unwrap().0, "A");
-        // lookup() finds stale data, deletes it
-        let absent = cache.lookup::<_, String>(&key, Duration::from_secs(10)).unwrap();
-        assert!(absent.is_none());
-        // now data is gone, even though this lookup() would have accepted it
-        let absent = cache.lookup::<_, String>(&key, Duration::from_secs(20)).unwrap();
+        remote.store(&cache, &key, &"A".to_string(), Duration::from_secs(10)).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        let absent = remote.lookup::<_, String>(&cache, &key, Duration::from_millis(10)).unwrap();
         assert!(absent.is_none());
     }
 
     #[test]
-    fn scoped() {
+    fn unreachable() {
         let dir = TestDir::temp();
-        let key = "foo".to_string();
-        let val_a = "A".to_string();
-        let val_b = "B".to_string();
-        let cache = Cache::new(&dir.root());
-        let cache_scoped = Cache::new(&dir.root()).scoped("scope".into());
-
-        cache.store(&key, &val_a, Duration::from_secs(100)).unwrap();
-        cache_scoped.store(&key, &val_b, Duration::from_secs(100)).unwrap();
+        let cache = Cache::new(dir.root());
+        // Nothing is listening on the port once the listener is dropped
+        let url = format!("http://{}", TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap());
+        let remote = RemoteCache::new(&url, Duration::from_secs(10));
+        assert!(remote.lookup::<_, String>(&cache, &"foo".to_string(), Duration::from_secs(10)).is_err());
+        assert!(remote.store(&cache, &"foo".to_string(), &"A".to_string(), Duration::from_secs(10)).is_err());
+    }
 
-        let present = cache.lookup::<_, String>(&key, Duration::from_secs(20)).unwrap();
-        assert_eq!(present.unwrap().0, "A");
-        let present_scoped = cache_scoped.lookup::<_, String>(&key, Duration::from_secs(20)).unwrap();
-        assert_eq!(present_scoped.unwrap().0, "B");
+    #[test]
+    fn unresponsive() {
+        let dir = TestDir::temp();
+        let cache = Cache::new(dir.root());
+        // Connections are queued by the OS but never accepted, so requests get no response
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let remote = RemoteCache::new(&format!("http://{}", listener.local_addr().unwrap()), Duration::from_millis(100));
+        let start = std::time::Instant::now();
+        assert!(remote.lookup::<_, String>(&cache, &"foo".to_string(), Duration::from_secs(10)).is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
     }
 
     #[test]
-    fn cleanup() {
+    fn escaped_scope() {
         let dir = TestDir::temp();
+        let remote = RemoteCache::new(&serve(), Duration::from_secs(10));
         let key = "foo".to_string();
-        let val = "A".to_string();
-        let cache = Cache::new(&dir.root());
-
-        cache.store(&key, &val, Duration::from_secs(5)).unwrap();
-        make_dir_stale(dir.root(), Duration::from_secs(10)).unwrap();
-        cache.cleanup().unwrap();
-
-        assert_eq!(dir_contents(dir.root()), ["last_cleanup"]); // keys and data dirs are now empty
+        let cache = Cache::new(dir.root()).scoped("a".into());
+        let escaped = Cache::new(dir.root()).scoped("a/b?c#d".into());
+        assert!(remote.url(&escaped, "key").ends_with("/cache/a%2Fb%3Fc%23d.key"));
 
-        let absent = cache.lookup::<_, String>(&key, Duration::from_secs(20)).unwrap();
+        remote.store(&escaped, &key, &"A".to_string(), Duration::from_secs(10)).unwrap();
+        let present = remote.lookup::<_, String>(&escaped, &key, Duration::from_secs(10)).unwrap();
+        assert_eq!(present.unwrap().0, "A");
+        let absent = remote.lookup::<_, String>(&cache, &key, Duration::from_secs(10)).unwrap();
         assert!(absent.is_none());
     }
 }
@@ -706,8 +3253,20 @@ mod cache_tests {
 #[derive(Clone, Debug)]
 pub struct Bkt {
     cache: Cache,
+    // Slower tiers consulted in order when `cache` misses, e.g. a directory shared between hosts
+    shared_tiers: Vec<Cache>,
+    #[cfg(feature = "http")]
+    remote: Option<RemoteCache>,
     cleanup_on_refresh: bool,
+    cleanup_policy: CleanupPolicy,
+    retry_policy: RetryPolicy,
+    rate_limit: Option<RateLimit>,
+    tag_rate_limit: Option<RateLimit>,
+    output_limit: Option<OutputLimit>,
+    stream_output: bool,
+    record_interleaving: bool,
     persist_failures: bool,
+    track_stats: bool,
 }
 
 impl Bkt {
@@ -734,29 +3293,321 @@ impl Bkt {
     ///
     /// If preparing the cache directory under `root_dir` fails.
     pub fn create(root_dir: PathBuf) -> Result<Self> {
-        // Note the cache is invalidated when the minor version changes
-        // TODO use separate directories per user, like bash-cache
-        //      See https://stackoverflow.com/q/57951893/113632
-        let cache_dir = root_dir
-            .join(format!("bkt-{}.{}-cache", env!("CARGO_PKG_VERSION_MAJOR"), env!("CARGO_PKG_VERSION_MINOR")));
+        let cache_dir = Bkt::cache_dir(&root_dir);
         Bkt::restrict_dir(&cache_dir)?;
         Ok(Bkt {
             cache: Cache::new(&cache_dir),
+            shared_tiers: vec![],
+            #[cfg(feature = "http")]
+            remote: None,
             cleanup_on_refresh: true,
+            cleanup_policy: CleanupPolicy::default(),
+            retry_policy: RetryPolicy::default(),
+            rate_limit: None,
+            tag_rate_limit: None,
+            output_limit: None,
+            stream_output: false,
+            record_interleaving: false,
             persist_failures: true,
+            track_stats: true,
         })
     }
 
+    /// Creates a new Bkt instance that reads from the cache under `root_dir` but never writes to
+    /// it, e.g. to use a pre-warmed cache mounted on a read-only filesystem. Cache misses and
+    /// expired entries still execute the command, but the result is not stored, expired data is
+    /// left in place, and no cleanups or stats updates are performed. Operations that can only
+    /// modify the cache, such as [`Bkt::invalidate_tag()`] and [`Bkt::import()`], fail.
+    ///
+    /// Unlike [`Bkt::create()`] the cache directory is not created if it does not exist.
+    pub fn read_only(root_dir: PathBuf) -> Self {
+        Bkt {
+            cache: Cache::new(Bkt::cache_dir(&root_dir)).read_only(true),
+            shared_tiers: vec![],
+            #[cfg(feature = "http")]
+            remote: None,
+            cleanup_on_refresh: false,
+            cleanup_policy: CleanupPolicy::default(),
+            retry_policy: RetryPolicy::default(),
+            rate_limit: None,
+            tag_rate_limit: None,
+            output_limit: None,
+            stream_output: false,
+            record_interleaving: false,
+            persist_failures: true,
+            track_stats: false,
+        }
+    }
+
+    /// Like [`Bkt::read_only()`], using the same cache location as [`Bkt::in_tmp()`].
+    pub fn read_only_in_tmp() -> Self {
+        Bkt::read_only(Bkt::temp_dir())
+    }
+
+    fn cache_dir(root_dir: &Path) -> PathBuf {
+        // Note the cache is invalidated when the minor version changes
+        // TODO use separate directories per user, like bash-cache
+        //      See https://stackoverflow.com/q/57951893/113632
+        root_dir.join(format!("bkt-{}.{}-cache", env!("CARGO_PKG_VERSION_MAJOR"), env!("CARGO_PKG_VERSION_MINOR")))
+    }
+
     /// Associates a scope with this Bkt instance, causing it to namespace its cache keys so that
     /// they do not collide with other instances using the same cache directory. This is useful when
     /// separate applications could potentially invoke the same commands but should not share a
     /// cache. Consider using the application's name, PID, and/or a timestamp in order to create a
     /// sufficiently unique namespace.
     pub fn scoped(mut self, scope: String) -> Self {
-        self.cache = self.cache.scoped(scope);
+        self.cache = self.cache.scoped(scope.clone());
+        self.shared_tiers = self.shared_tiers.into_iter().map(|t| t.scoped(scope.clone())).collect();
+        self
+    }
+
+    /// Associates a tag with invocations cached by this Bkt instance. Tags do not affect lookups,
+    /// but all entries stored with a given tag can later be expired at once with
+    /// [`Bkt::invalidate_tag()`], without needing to know their exact commands. This can be called
+    /// multiple times to apply several tags.
+    ///
+    /// Tags must not be empty, `.` or `..`, or contain a path separator; caching results with such
+    /// a tag fails.
+    pub fn tagged(mut self, tag: String) -> Self {
+        self.cache = self.cache.tagged(tag.clone());
+        self.shared_tiers = self.shared_tiers.into_iter().map(|t| t.tagged(tag.clone())).collect();
+        self
+    }
+
+    /// Adds a cache directory under `root_dir` as an additional, slower tier behind this
+    /// instance's own cache, such as a directory on a network filesystem shared by a team. Lookups
+    /// check this instance's cache first and then each shared tier in the order they were added;
+    /// a hit in a shared tier is copied into the faster tiers (keeping its original age) so later
+    /// lookups are fast. Newly executed invocations are written to every tier, though failing to
+    /// write to a shared tier is only logged. If `max_ttl` is set results in this tier are never
+    /// considered valid for longer than that, whatever TTL is requested; see [`Bkt::max_ttl()`] to
+    /// do the same for this instance's own cache.
+    ///
+    /// Shared tiers use the same scope, tags, and read-only mode as this instance. Statistics,
+    /// [`Bkt::export()`], and [`Bkt::import()`] only involve this instance's own cache. Since
+    /// secret environment variables must be keyed consistently across tiers, the salt of the
+    /// last shared tier is used for them (see [`CommandDesc::with_secret_env()`]).
+    ///
+    /// ```no_run
+    /// # fn main() -> anyhow::Result<()> {
+    /// # use std::time::Duration;
+    /// let bkt = bkt::Bkt::in_tmp()?
+    ///     .max_ttl(Duration::from_secs(300))
+    ///     .shared_tier("/mnt/team/cache".into(), None)?;
+    /// bkt.retrieve(&bkt::CommandDesc::new(["expensive", "report"]), Duration::from_secs(3600))?;
+    /// # Ok(()) }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// If preparing the cache directory under `root_dir` fails.
+    pub fn shared_tier(mut self, root_dir: PathBuf, max_ttl: Option<Duration>) -> Result<Self> {
+        let cache_dir = Bkt::cache_dir(&root_dir);
+        if !self.cache.read_only {
+            Bkt::restrict_dir(&cache_dir)?;
+        }
+        self.shared_tiers.push(Cache { cache_dir, ..self.cache.clone() }.max_ttl(max_ttl));
+        Ok(self)
+    }
+
+    /// Shares cached invocations with other machines via an HTTP server at `base_url`, such as a
+    /// Bazel-style remote cache or a WebDAV-enabled directory served by nginx. Entries are
+    /// fetched with `GET <base_url>/<key>` after any local tiers miss, and copied into the local
+    /// tiers when found; newly executed invocations are also written with `PUT <base_url>/<key>`.
+    /// Network and server errors are logged and otherwise ignored, so if the server is unavailable
+    /// this instance simply falls back to its local cache.
+    ///
+    /// Each request gives up after `timeout`, bounding how long an unresponsive server can delay
+    /// a lookup or store.
+    ///
+    /// Like [`Bkt::shared_tier()`], entries keyed on secret environment variables can only be
+    /// shared between machines that use the same salt. Requires the `http` feature.
+    #[cfg(feature = "http")]
+    pub fn remote_cache(mut self, base_url: &str, timeout: Duration) -> Self {
+        self.remote = Some(RemoteCache::new(base_url, timeout));
+        self
+    }
+
+    /// Caps how long results in this instance's own cache are considered valid, whatever TTL is
+    /// requested. This is mostly useful alongside [`Bkt::shared_tier()`], to keep a fast local
+    /// cache fresher than a shared one.
+    pub fn max_ttl(mut self, max_ttl: Duration) -> Self {
+        self.cache = self.cache.max_ttl(Some(max_ttl));
         self
     }
 
+    /// Expires all cached invocations that were stored with the given tag, regardless of their
+    /// command or scope. Returns the number of entries that were expired.
+    ///
+    /// ```no_run
+    /// # fn main() -> anyhow::Result<()> {
+    /// # use std::time::Duration;
+    /// let bkt = bkt::Bkt::in_tmp()?.tagged("deploy".into());
+    /// bkt.retrieve(&bkt::CommandDesc::new(["kubectl", "get", "pods"]), Duration::from_secs(600))?;
+    /// // ... later, after a deploy
+    /// bkt.invalidate_tag("deploy")?;
+    /// # Ok(()) }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// If the cache directory cannot be read or modified.
+    pub fn invalidate_tag(&self, tag: &str) -> Result<usize> {
+        self.tiers().map(|tier| tier.invalidate_tag(tag)).sum()
+    }
+
+    /// Removes everything this instance has stored in its scope (see [`Bkt::scoped()`]): cached
+    /// invocations, usage statistics, watch registrations, and per-command rate limit budgets.
+    /// This is useful for discarding a short-lived scope once it's no longer needed. An unscoped
+    /// instance clears everything stored without a scope. Returns the number of cached
+    /// invocations removed.
+    ///
+    /// # Errors
+    ///
+    /// If the cache directory cannot be read or modified.
+    pub fn clear(&self) -> Result<usize> {
+        self.tiers().map(|tier| tier.clear()).sum()
+    }
+
+    /// Records that the given command's cached result depends on the given paths, so that
+    /// [`Bkt::watch()`] can invalidate or refresh it (as specified by `on_change`) as soon as any
+    /// of them change, rather than waiting for its TTL to expire. Directories are watched for
+    /// changes to their immediate contents, not recursively. Relative paths are resolved against
+    /// the current directory, which is also where a command without a working directory (see
+    /// [`CommandDesc::with_working_dir()`]) is refreshed. Typically this is called after
+    /// [`Bkt::retrieve()`]; the registration is removed by cleanups once the command's result is
+    /// no longer cached.
+    ///
+    /// ```no_run
+    /// # fn main() -> anyhow::Result<()> {
+    /// # use std::time::Duration;
+    /// let bkt = bkt::Bkt::in_tmp()?;
+    /// let cmd = bkt::CommandDesc::new(["kubectl", "config", "get-contexts"]);
+    /// let ttl = Duration::from_secs(3600);
+    /// bkt.retrieve(&cmd, ttl)?;
+    /// bkt.register_watch(&cmd, ["/home/me/.kube/config"], ttl, bkt::OnChange::Refresh)?;
+    /// # Ok(()) }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// If the cache directory cannot be written to, or the command has secret environment
+    /// variables (see [`CommandDesc::with_secret_env()`]) and `on_change` is
+    /// [`OnChange::Refresh`], since their values aren't persisted for the watcher to use.
+    pub fn register_watch<I, P>(&self, command: &CommandDesc, paths: I, ttl: Duration, on_change: OnChange)
+            -> Result<()> where I: IntoIterator<Item=P>, P: AsRef<Path> {
+        if on_change == OnChange::Refresh && !command.secret_values.0.is_empty() {
+            return Err(Error::msg("Commands with secret environment variables cannot be refreshed by a watcher"));
+        }
+        let command = self.seal_secrets(command)?.into_owned();
+        let cwd = std::env::current_dir()?;
+        let entry = WatchEntry {
+            paths: paths.into_iter().map(|p| cwd.join(p)).collect(),
+            cwd,
+            ttl,
+            scope: self.cache.scope.clone(),
+            tags: self.cache.tags.clone(),
+            on_change,
+            command,
+        };
+        self.cache.register_watch(&entry.command, &entry)
+    }
+
+    /// Watches the paths registered with [`Bkt::register_watch()`] and invalidates or refreshes
+    /// the corresponding cached results as soon as any of them change, until `stop` is set. New
+    /// registrations are picked up as they're made. If this instance is scoped only commands in
+    /// its scope are watched.
+    ///
+    /// This is only supported on Linux.
+    ///
+    /// # Errors
+    ///
+    /// If the cache directory cannot be read or modified, or watching fails, e.g. because the
+    /// system's limit on inotify watches has been reached. Failures to refresh individual commands
+    /// are logged rather than returned.
+    #[cfg(target_os = "linux")]
+    pub fn watch(&self, stop: &std::sync::atomic::AtomicBool) -> Result<()> {
+        use std::sync::atomic::Ordering;
+        std::fs::create_dir_all(self.cache.watch_dir())?;
+        while !stop.load(Ordering::SeqCst) {
+            let watches = self.cache.watches()?;
+            let mut inotify = Inotify::new()?;
+            // Changes to the registrations themselves trigger a reload
+            inotify.add(&self.cache.watch_dir())?;
+            for path in watches.iter().flat_map(|(_, w)| &w.paths) {
+                let dir = if path.is_dir() { Some(path.as_path()) } else { path.parent() };
+                match dir {
+                    Some(dir) if dir.is_dir() => inotify.add(dir)?,
+                    _ => debug!("watch skipping {}, no such directory", path.display()),
+                }
+            }
+            debug!("watching {} commands", watches.len());
+            while !stop.load(Ordering::SeqCst) {
+                let mut changed = inotify.read(Duration::from_millis(200))?;
+                if changed.is_empty() {
+                    continue;
+                }
+                // Let bursts of changes (e.g. an editor saving a file) settle before acting on them
+                loop {
+                    let more = inotify.read(Duration::from_millis(50))?;
+                    if more.is_empty() { break; }
+                    changed.extend(more);
+                }
+                for (key_file, watch) in &watches {
+                    if changed.iter().any(|c| watch.paths.iter().any(|p| c.starts_with(p))) {
+                        self.handle_change(key_file, watch);
+                    }
+                }
+                if changed.iter().any(|c| c.starts_with(self.cache.watch_dir())) {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Watching is only supported on Linux.
+    ///
+    /// # Errors
+    ///
+    /// Always.
+    #[cfg(not(target_os = "linux"))]
+    pub fn watch(&self, _stop: &std::sync::atomic::AtomicBool) -> Result<()> {
+        Err(Error::msg("Watching for changes is only supported on Linux"))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn handle_change(&self, key_file: &OsStr, watch: &WatchEntry) {
+        let command = watch.command.args[0].to_string_lossy();
+        match watch.on_change {
+            OnChange::Invalidate => {
+                for tier in self.tiers() {
+                    match tier.remove_key_file(key_file) {
+                        Ok(removed) => debug!("watch invalidated {} ({})", command, removed),
+                        Err(e) => warn!("failed to invalidate {}: {:?}", command, e),
+                    }
+                }
+            },
+            OnChange::Refresh => {
+                let mut bkt = self.clone();
+                if let (Some(scope), None) = (&watch.scope, &self.cache.scope) {
+                    bkt = bkt.scoped(scope.clone());
+                }
+                for tag in &watch.tags {
+                    bkt = bkt.tagged(tag.clone());
+                }
+                // Run where the command was registered from, as the original invocation was
+                let mut refresh = watch.command.clone();
+                refresh.run_dir = RunDir(Some(watch.cwd.clone()));
+                match bkt.refresh(&refresh, watch.ttl) {
+                    Ok(_) => debug!("watch refreshed {}", command),
+                    Err(e) => warn!("failed to refresh {}: {:?}", command, e),
+                }
+            },
+        }
+    }
+
     /// By default a background cleanup thread runs on cache misses and calls to [`Bkt::refresh()`]
     /// to remove stale data. You may prefer to manage cleanup yourself if you expect frequent cache
     /// misses and want to minimize the number of threads being created. See [`Bkt::cleanup_once()`]
@@ -766,6 +3617,77 @@ impl Bkt {
         self
     }
 
+    /// Configures how often and how aggressively this instance cleans up stale data, see
+    /// [`CleanupPolicy`].
+    pub fn cleanup_policy(mut self, policy: CleanupPolicy) -> Self {
+        self.cleanup_policy = policy;
+        self
+    }
+
+    /// Configures this instance to re-run commands that fail, see [`RetryPolicy`]. Only the
+    /// result of the last attempt is returned and cached, so a command that fails transiently can
+    /// be cached once it succeeds. Retries also apply to [`Bkt::refresh()`].
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+
+    /// Limits how often each command may be executed, across all processes using this cache
+    /// directory, to protect the resources it calls from a stampede of cache misses or refreshes.
+    /// When executing a command would exceed the limit its most recent cached result is returned
+    /// instead, even if it has expired (so long as it hasn't been cleaned up); if there is no such
+    /// result a [`RateLimited`] error is returned.
+    ///
+    /// ```no_run
+    /// # fn main() -> anyhow::Result<()> {
+    /// # use std::time::Duration;
+    /// let bkt = bkt::Bkt::in_tmp()?.rate_limit("1/10s".parse()?);
+    /// match bkt.retrieve(&bkt::CommandDesc::new(["curl", "https://example.com"]), Duration::from_secs(5)) {
+    ///     Err(e) if e.is::<bkt::RateLimited>() => { /* try again later */ },
+    ///     result => { result?; },
+    /// }
+    /// # Ok(()) }
+    /// ```
+    pub fn rate_limit(mut self, limit: RateLimit) -> Self {
+        self.rate_limit = Some(limit);
+        self
+    }
+
+    /// Like [`Bkt::rate_limit()`], but limits executions of _all_ commands that share a tag (see
+    /// [`Bkt::tagged()`]), e.g. to protect a backend that several different commands call. Each of
+    /// this instance's tags has its own budget, and an execution must fit within all of them.
+    pub fn tag_rate_limit(mut self, limit: RateLimit) -> Self {
+        self.tag_rate_limit = Some(limit);
+        self
+    }
+
+    /// Limits how much output is captured from commands this instance executes, see
+    /// [`OutputLimit`]. Output beyond the limit is read and discarded, and
+    /// [`Invocation::truncated()`] reports whether this happened.
+    pub fn output_limit(mut self, limit: OutputLimit) -> Self {
+        self.output_limit = Some(limit);
+        self
+    }
+
+    /// When enabled, commands this instance executes have their output written to this process's
+    /// stdout and stderr as it is produced, in addition to being captured. This way callers see
+    /// output immediately, and in full even if it exceeds the [`OutputLimit`]. Invocations that
+    /// were streamed report so via [`Invocation::streamed()`]. If a command is retried (see
+    /// [`Bkt::retry_policy()`]) the output of every attempt is streamed.
+    pub fn stream_output(mut self, stream_output: bool) -> Self {
+        self.stream_output = stream_output;
+        self
+    }
+
+    /// When enabled, commands this instance executes record the order (and timing) in which they
+    /// wrote to stdout and stderr, so that [`Invocation::replay()`] can reproduce the original
+    /// interleaving rather than writing all of stdout before all of stderr. This adds a small
+    /// amount of overhead to every cached result.
+    pub fn record_interleaving(mut self, record_interleaving: bool) -> Self {
+        self.record_interleaving = record_interleaving;
+        self
+    }
+
     /// Configures this instance to not cache invocations that return non-zero exit codes. This only
     /// affects _writing_ to the cache; if a failed invocation has already been cached (e.g. by a
     /// different instance) that data will still be used until it expires.
@@ -781,6 +3703,14 @@ impl Bkt {
         self
     }
 
+    /// By default each call to [`Bkt::retrieve()`] and [`Bkt::refresh()`] updates the usage
+    /// statistics reported by [`Bkt::stats()`], which costs an additional small file write. Pass
+    /// `false` to skip recording statistics for this instance.
+    pub fn track_stats(mut self, track_stats: bool) -> Self {
+        self.track_stats = track_stats;
+        self
+    }
+
     #[cfg(not(unix))]
     fn restrict_dir(_cache_dir: &Path) -> Result<()> { Ok(()) }
     #[cfg(unix)]
@@ -796,23 +3726,97 @@ impl Bkt {
         Ok(())
     }
 
-    fn execute_subprocess(desc: &CommandDesc) -> Result<Invocation> {
+    fn execute_subprocess(&self, desc: &CommandDesc) -> Result<Invocation> {
         let mut cmd: std::process::Command = desc.into();
+        cmd.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped());
+        let mut pty = None;
+        if desc.pty {
+            let (master, slave) = PtyMaster::open()?;
+            cmd.stdout(slave);
+            pty = Some(master);
+        }
         let start = Instant::now();
-        // TODO write to stdout/stderr while running, rather than after the process completes?
-        // See https://stackoverflow.com/q/66060139
-        let result = cmd.output()
+        let mut child = cmd.spawn()
             .with_context(|| format!("Failed to run command {}", desc.args[0].to_string_lossy()))?;
+        // Close our copy of the pty's slave end, otherwise reading the master never finishes
+        std::mem::drop(cmd);
+        let stdout: Box<dyn io::Read + Send> = match pty {
+            Some(master) => Box::new(master),
+            None => Box::new(child.stdout.take().expect("Piped")),
+        };
+
+        // Read both pipes concurrently so the child never blocks on a full pipe, even once the
+        // output limit is reached and further output is discarded
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let readers: Vec<_> = vec![
+            (Stream::Stdout, stdout),
+            (Stream::Stderr, Box::new(child.stderr.take().expect("Piped"))),
+        ].into_iter().map(|(stream, mut pipe)| {
+            let sender = sender.clone();
+            std::thread::spawn(move || -> io::Result<()> {
+                let mut buf = [0; 8192];
+                loop {
+                    match pipe.read(&mut buf) {
+                        Ok(0) => return Ok(()),
+                        Ok(n) => { let _ = sender.send((stream, buf[..n].to_vec())); },
+                        Err(e) if e.kind() == ErrorKind::Interrupted => {},
+                        Err(e) => return Err(e),
+                    }
+                }
+            })
+        }).collect();
+        std::mem::drop(sender);
+
+        let mut output = CapturedOutput::new(self.output_limit, self.record_interleaving, start);
+        for (stream, chunk) in receiver {
+            if self.stream_output {
+                // Failing to echo the output shouldn't prevent it from being cached
+                let _ = match stream {
+                    Stream::Stdout => io::stdout().write_all(&chunk).and_then(|_| io::stdout().flush()),
+                    Stream::Stderr => io::stderr().write_all(&chunk),
+                };
+            }
+            output.push(stream, &chunk);
+        }
+        for reader in readers {
+            reader.join().expect("reader thread panicked").context("Failed to read subprocess output")?;
+        }
+        let status = child.wait().context("Failed to wait for subprocess")?;
         let runtime = start.elapsed();
+        output.finish();
+        let truncated = output.overflowed.is_some();
+        if truncated {
+            debug!("output of {} exceeded {:?}", desc.args[0].to_string_lossy(), self.output_limit);
+        }
         Ok(Invocation {
-            stdout: result.stdout,
-            stderr: result.stderr,
+            stdout: output.stdout,
+            stderr: output.stderr,
             // TODO handle signals, see https://stackoverflow.com/q/66272686
-            exit_code: result.status.code().unwrap_or(126),
+            exit_code: status.code().unwrap_or(126),
             runtime,
+            attempts: 1,
+            truncated,
+            chunks: output.chunks,
+            streamed: self.stream_output,
         })
     }
 
+    /// Executes the command, retrying it as configured by the retry policy.
+    fn execute(&self, desc: &CommandDesc) -> Result<Invocation> {
+        let mut result = self.execute_subprocess(desc)?;
+        while self.retry_policy.should_retry(&result) {
+            let delay = self.retry_policy.delay(result.attempts);
+            debug!("attempt {} exited with {}, retrying in {:?}", result.attempts, result.exit_code, delay);
+            std::thread::sleep(delay);
+            let (attempts, runtime) = (result.attempts, result.runtime);
+            result = self.execute_subprocess(desc)?;
+            result.attempts = attempts + 1;
+            // Only count time spent running the command, not waiting between attempts
+            result.runtime += runtime;
+        }
+        Ok(result)
+    }
+
     /// Looks up the given command in Bkt's cache, returning it, and its age, if found and newer
     /// than the given TTL.
     ///
@@ -827,15 +3831,24 @@ impl Bkt {
     //     in execute_subprocess(). See https://rust-lang.github.io/api-guidelines/flexibility.html
     //     See also C-BUILDER in https://rust-lang.github.io/api-guidelines/type-safety.html
     pub fn retrieve(&self, command: &CommandDesc, ttl: Duration) -> Result<(Invocation, Duration)> {
-        let cached = self.cache.lookup(command, ttl).context("Cache lookup failed")?;
+        let command = &*self.seal_secrets(command)?;
+        let (cached, expired) = self.lookup_unexpired(command, ttl)?;
+        let cached = match cached {
+            None => self.check_rate_limit(command, expired)?,
+            hit => hit,
+        };
         let result = match cached {
-            Some((cached, mtime)) => (cached, mtime.elapsed()?),
+            Some((cached, mtime)) => self.cache_hit(command, cached, mtime)?,
             None => {
                 let cleanup_hook = self.maybe_cleanup_once();
-                let result = Bkt::execute_subprocess(command).context("Subprocess execution failed")?;
-                if self.persist_failures || result.exit_code == 0 {
-                    self.cache.store(command, &result, ttl).context("Cache write failed")?;
+                let result = self.execute(command).context("Subprocess execution failed")?;
+                if self.should_store(&result) {
+                    self.store(command, &result, ttl).context("Cache write failed")?;
                 }
+                self.record_stats(command, |s: &mut CommandStats| {
+                    s.misses += 1;
+                    if result.exit_code != 0 { s.failures += 1; }
+                });
                 Bkt::join_cleanup_thread(cleanup_hook);
                 (result, Duration::default())
             }
@@ -843,6 +3856,92 @@ impl Bkt {
         Ok(result)
     }
 
+    /// Looks up an unexpired result for the given command, along with any expired result that
+    /// should be used instead of executing the command if it's rate limited.
+    #[allow(clippy::type_complexity)]
+    fn lookup_unexpired(&self, command: &CommandDesc, ttl: Duration)
+            -> Result<(Option<(Invocation, SystemTime)>, Option<(Invocation, SystemTime)>)> {
+        // When rate limited an expired result may be needed, so it must not be removed from this
+        // instance's own cache by a regular lookup()
+        if self.is_rate_limited() {
+            match self.cache.lookup_ignoring_ttl::<_, Invocation>(command).context("Cache lookup failed")? {
+                Some((cached, mtime)) if mtime.elapsed().map(|age| age <= self.cache.capped_ttl(ttl)).unwrap_or(false) =>
+                    Ok((Some((cached, mtime)), None)),
+                expired => Ok((self.lookup(command, ttl, 1).context("Cache lookup failed")?, expired)),
+            }
+        } else {
+            Ok((self.lookup(command, ttl, 0).context("Cache lookup failed")?, None))
+        }
+    }
+
+    fn cache_hit(&self, command: &CommandDesc, cached: Invocation, mtime: SystemTime) -> Result<(Invocation, Duration)> {
+        self.record_stats(command, |s: &mut CommandStats| {
+            s.hits += 1;
+            s.saved += cached.runtime;
+        });
+        Ok((cached, mtime.elapsed()?))
+    }
+
+    fn lookup_hit(&self, command: &CommandDesc, ttl: Duration) -> Result<Option<(Invocation, Duration)>> {
+        let command = &*self.seal_secrets(command)?;
+        match self.lookup(command, ttl, 0).context("Cache lookup failed")? {
+            Some((cached, mtime)) => self.cache_hit(command, cached, mtime).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Like [`Bkt::retrieve()`], but for many commands at once. Cached results are looked up
+    /// first, then commands that miss the cache are executed concurrently by up to `parallelism`
+    /// threads. On a miss the cache is cleaned up once for the whole batch, rather than once per
+    /// command. Results are returned in the same order as the given commands.
+    ///
+    /// Note that with [`Bkt::stream_output()`] enabled the output of concurrently executed commands
+    /// will be interleaved.
+    ///
+    /// # Errors
+    ///
+    /// Each command's result is returned separately, so that one command failing doesn't prevent
+    /// the others' results from being returned. See [`Bkt::retrieve()`] for reasons a command
+    /// can fail.
+    pub fn retrieve_all(&self, commands: &[CommandDesc], ttl: Duration, parallelism: usize)
+            -> Vec<Result<(Invocation, Duration)>> {
+        let mut results: Vec<Option<Result<(Invocation, Duration)>>> = Vec::with_capacity(commands.len());
+        let mut misses = Vec::new();
+        for (i, command) in commands.iter().enumerate() {
+            // Rate limits need retrieve()'s more careful handling of expired results
+            let hit = if self.is_rate_limited() { Ok(None) } else { self.lookup_hit(command, ttl) };
+            if let Ok(None) = hit {
+                misses.push(i);
+            }
+            results.push(hit.transpose());
+        }
+        if misses.is_empty() {
+            return results.into_iter().map(|r| r.expect("All hits")).collect();
+        }
+
+        let cleanup_hook = self.maybe_cleanup_once();
+        let worker = self.clone().cleanup_on_refresh(false);
+        let next = std::sync::atomic::AtomicUsize::new(0);
+        let executed: Vec<_> = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..parallelism.max(1).min(misses.len())).map(|_| scope.spawn(|| {
+                let mut executed = Vec::new();
+                loop {
+                    let n = next.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                    match misses.get(n) {
+                        Some(&i) => executed.push((i, worker.retrieve(&commands[i], ttl))),
+                        None => return executed,
+                    }
+                }
+            })).collect();
+            threads.into_iter().flat_map(|t| t.join().expect("retrieve_all worker panicked")).collect()
+        });
+        Bkt::join_cleanup_thread(cleanup_hook);
+        for (i, result) in executed {
+            results[i] = Some(result);
+        }
+        results.into_iter().map(|r| r.expect("All commands retrieved")).collect()
+    }
+
     /// Unconditionally executes the given command and caches the invocation for the given TTL.
     /// This can be used to "warm" the cache so that subsequent calls to `execute` are fast.
     ///
@@ -851,15 +3950,283 @@ impl Bkt {
     /// If executing or serializing the command fails. This generally reflects a user error such as
     /// an invalid command.
     pub fn refresh(&self, command: &CommandDesc, ttl: Duration) -> Result<Invocation> {
+        let command = &*self.seal_secrets(command)?;
+        self.refresh_counting(command, ttl, |s| s.refreshes += 1)
+    }
+
+    /// Implements refresh() for an already-sealed command, recording the execution in the
+    /// command's stats with `count`.
+    fn refresh_counting<F: FnOnce(&mut CommandStats)>(&self, command: &CommandDesc, ttl: Duration, count: F)
+            -> Result<Invocation> {
+        if self.is_rate_limited() {
+            let last = self.cache.lookup_ignoring_ttl(command).context("Cache lookup failed")?;
+            if let Some((last, _)) = self.check_rate_limit(command, last)? {
+                return Ok(last);
+            }
+        }
         let cleanup_hook = self.maybe_cleanup_once();
-        let result = Bkt::execute_subprocess(command).context("Subprocess execution failed")?;
-        if self.persist_failures || result.exit_code == 0 {
-            self.cache.store(command, &result, ttl).context("Cache write failed")?;
+        let result = self.execute(command).context("Subprocess execution failed")?;
+        if self.should_store(&result) {
+            self.store(command, &result, ttl).context("Cache write failed")?;
         }
+        self.record_stats(command, |s: &mut CommandStats| {
+            count(s);
+            if result.exit_code != 0 { s.failures += 1; }
+        });
         Bkt::join_cleanup_thread(cleanup_hook);
         Ok(result)
     }
 
+    /// Like [`Bkt::retrieve()`], but on a cache miss the given `probe` command is also executed
+    /// (before the command itself) and its output is cached alongside the command's, so that
+    /// [`Bkt::refresh_if_changed()`] can later avoid re-executing the command.
+    ///
+    /// # Errors
+    ///
+    /// If executing the probe fails, or any of the reasons [`Bkt::retrieve()`] can fail.
+    pub fn retrieve_validated(&self, command: &CommandDesc, probe: &CommandDesc, ttl: Duration)
+            -> Result<(Invocation, Duration)> {
+        let sealed = &*self.seal_secrets(command)?;
+        // Only probe when there's no unexpired result; any expired one is handled by refresh()
+        if let (Some((cached, mtime)), _) = self.lookup_unexpired(sealed, ttl)? {
+            return self.cache_hit(sealed, cached, mtime);
+        }
+        let (result, executed) = self.refresh_if_changed(command, probe, ttl)?;
+        if !executed {
+            // Unlike a background revalidation, this call is served the cached result
+            self.record_stats(sealed, |s: &mut CommandStats| {
+                s.hits += 1;
+                s.saved += result.runtime;
+            });
+        }
+        Ok((result, Duration::default()))
+    }
+
+    /// Refreshes the given command only if a cheaper `probe` command indicates it may have
+    /// changed. The probe is executed first and its output compared to its output when the
+    /// command was last cached (see [`Bkt::retrieve_validated()`]); if it exited successfully
+    /// both times with the same output the cached invocation's age is reset without re-executing
+    /// the command. Otherwise this behaves like [`Bkt::refresh()`]. Returns the invocation and
+    /// whether the command was executed.
+    ///
+    /// Suitable probes are commands whose output changes whenever the command's would, such as
+    /// `git rev-parse HEAD` or fetching an HTTP resource's `ETag`.
+    ///
+    /// # Errors
+    ///
+    /// If executing the probe fails, or any of the reasons [`Bkt::refresh()`] can fail.
+    pub fn refresh_if_changed(&self, command: &CommandDesc, probe: &CommandDesc, ttl: Duration)
+            -> Result<(Invocation, bool)> {
+        use sha2::{Digest, Sha256};
+        let command = &*self.seal_secrets(command)?;
+        let mut probe_cmd: Command = probe.into();
+        let output = probe_cmd.stdin(Stdio::null()).stderr(Stdio::null()).output()
+            .with_context(|| format!("Failed to run probe command {}", probe.args[0].to_string_lossy()))?;
+        let digest: Option<String> = if output.status.success() {
+            Some(hex(&Sha256::digest(&output.stdout)))
+        } else {
+            None
+        };
+        let key = ProbeKey { command: command.clone(), probe: probe.clone() };
+        // Checked up front, since looking up an expired result removes it
+        let existed = self.cache.contains(command);
+
+        if let Some(digest) = &digest {
+            let last_digest = self.cache.lookup::<_, String>(&key, ttl).context("Cache lookup failed")?;
+            if last_digest.map(|(last, _)| &last == digest).unwrap_or(false) {
+                if let Some((cached, _)) = self.lookup(command, ttl, 0).context("Cache lookup failed")? {
+                    debug!("probe output unchanged, resetting age of {}", command.args[0].to_string_lossy());
+                    if self.should_store(&cached) {
+                        self.store(command, &cached, ttl).context("Cache write failed")?;
+                        self.cache.store(&key, digest, ttl).context("Cache write failed")?;
+                    }
+                    return Ok((cached, false));
+                }
+            }
+        }
+
+        // Executing the command only replaces a result if there was one to replace
+        let result = self.refresh_counting(command, ttl, |s| if existed { s.refreshes += 1 } else { s.misses += 1 })?;
+        if let Some(digest) = digest {
+            if self.should_store(&result) {
+                self.cache.store(&key, &digest, ttl).context("Cache write failed")?;
+            }
+        }
+        Ok((result, true))
+    }
+
+    /// Writes the unexpired contents of the cache to the given writer as a portable archive, which
+    /// can be loaded into another cache with [`Bkt::import()`], e.g. to pre-seed a new machine with
+    /// a warm cache. The archive includes each entry's command, output, scope, tags, store time,
+    /// and TTL. If this instance is scoped only entries in its scope are exported, otherwise
+    /// entries in all scopes are. Returns the number of entries exported.
+    ///
+    /// Note that entries keyed on secret environment variables (see
+    /// [`CommandDesc::with_secret_env()`]) will not be found once imported into a different cache
+    /// directory, since each cache directory salts secrets differently.
+    ///
+    /// # Errors
+    ///
+    /// If the cache cannot be read or writing to `writer` fails.
+    pub fn export<W: Write>(&self, writer: W) -> Result<usize> {
+        self.cache.export::<CommandDesc, Invocation, _>(writer)
+    }
+
+    /// Loads an archive created by [`Bkt::export()`] into this cache, preserving each entry's
+    /// scope, tags, and store time. If this instance is scoped only entries in its scope are
+    /// imported. Entries that have expired by the time they are imported are skipped; pass
+    /// `rebase_timestamps` to shift every entry's store time forward by the archive's age, so
+    /// entries are as fresh as they were when exported. Returns the number of entries imported.
+    ///
+    /// # Errors
+    ///
+    /// If the archive cannot be read or parsed, or writing to the cache fails.
+    pub fn import<R: io::Read>(&self, reader: R, rebase_timestamps: bool) -> Result<usize> {
+        self.cache.import::<CommandDesc, Invocation, _>(reader, rebase_timestamps)
+    }
+
+    /// Returns the usage statistics recorded for commands cached in this instance's scope, in no
+    /// particular order. Statistics are only recorded by instances with
+    /// [`track_stats`](Bkt::track_stats()) enabled (the default).
+    ///
+    /// # Errors
+    ///
+    /// If the stats directory exists but cannot be read.
+    pub fn stats(&self) -> Result<Vec<(CommandDesc, CommandStats)>> {
+        self.cache.stats()
+    }
+
+    /// Populates the digests of any secret environment variables using this cache's salt, so they
+    /// can participate in the cache key without being persisted.
+    fn seal_secrets<'a>(&self, command: &'a CommandDesc) -> Result<Cow<'a, CommandDesc>> {
+        use sha2::{Digest, Sha256};
+        if command.secret_values.0.is_empty() {
+            return Ok(Cow::Borrowed(command));
+        }
+        let salt = self.tiers().last().expect("Always at least one tier").secret_salt()?;
+        let mut sealed = command.clone();
+        sealed.secret_env = command.secret_values.0.iter().map(|(k, v)| {
+            let mut hasher = Sha256::new();
+            hasher.update(&salt);
+            hasher.update(k.as_encoded_bytes());
+            hasher.update([0]);
+            hasher.update(v.as_encoded_bytes());
+            let digest = hex(&hasher.finalize());
+            (k.clone(), digest)
+        }).collect();
+        Ok(Cow::Owned(sealed))
+    }
+
+    fn tiers(&self) -> impl Iterator<Item=&Cache> {
+        std::iter::once(&self.cache).chain(&self.shared_tiers)
+    }
+
+    /// Looks up the command in each tier in turn, starting with `first_tier`, copying a hit into
+    /// any faster tiers.
+    fn lookup(&self, command: &CommandDesc, ttl: Duration, first_tier: usize) -> Result<Option<(Invocation, SystemTime)>> {
+        for (i, tier) in self.tiers().enumerate().skip(first_tier) {
+            let cached: Option<(Invocation, _)> = tier.lookup(command, ttl)?;
+            if let Some((invocation, mtime)) = cached {
+                self.promote(i, command, &invocation, mtime, ttl);
+                return Ok(Some((invocation, mtime)));
+            }
+        }
+        #[cfg(feature = "http")]
+        if let Some(remote) = &self.remote {
+            match remote.lookup(&self.cache, command, ttl) {
+                Ok(Some((invocation, stored))) => {
+                    self.promote(self.tiers().count(), command, &invocation, stored, ttl);
+                    return Ok(Some((invocation, stored)));
+                },
+                Ok(None) => {},
+                Err(e) => warn!("remote cache lookup failed, using local cache only: {:?}", e),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Copies a hit into the first `count` tiers, unless it has already expired there.
+    fn promote(&self, count: usize, command: &CommandDesc, invocation: &Invocation, stored: SystemTime, ttl: Duration) {
+        if self.cache.read_only {
+            return;
+        }
+        for faster in self.tiers().take(count) {
+            if stored.elapsed().map(|age| age < faster.capped_ttl(ttl)).unwrap_or(false) {
+                // Promotion is just an optimization, the hit itself is still valid
+                if let Err(e) = faster.store_at(command, invocation, ttl, Some(stored)) {
+                    warn!("cache promotion failed: {:?}", e);
+                }
+            }
+        }
+    }
+
+    fn store(&self, command: &CommandDesc, result: &Invocation, ttl: Duration) -> Result<()> {
+        self.cache.store(command, result, ttl)?;
+        // Like the remote cache, shared tiers may be unavailable (e.g. an unmounted network
+        // filesystem) without preventing the local cache from being used
+        for tier in &self.shared_tiers {
+            if let Err(e) = tier.store(command, result, ttl) {
+                warn!("shared cache write failed, using local cache only: {:?}", e);
+            }
+        }
+        #[cfg(feature = "http")]
+        if let Some(remote) = &self.remote {
+            if let Err(e) = remote.store(&self.cache, command, result, ttl) {
+                warn!("remote cache write failed, using local cache only: {:?}", e);
+            }
+        }
+        Ok(())
+    }
+
+    fn is_rate_limited(&self) -> bool {
+        self.rate_limit.is_some() || (self.tag_rate_limit.is_some() && !self.cache.tags.is_empty())
+    }
+
+    /// Consumes an execution from the configured rate limits, returning None if the command can
+    /// be executed. Otherwise returns the given fallback result, or an error if there is none.
+    fn check_rate_limit(&self, command: &CommandDesc, fallback: Option<(Invocation, SystemTime)>)
+            -> Result<Option<(Invocation, SystemTime)>> {
+        let mut budgets = Vec::new();
+        if let Some(limit) = self.rate_limit {
+            budgets.push((format!("key.{}", self.cache.key_file(&command.cache_key())), limit));
+        }
+        if let Some(limit) = self.tag_rate_limit {
+            for tag in &self.cache.tags {
+                Cache::check_tag(tag)?;
+                budgets.push((format!("tag.{}", tag), limit));
+            }
+        }
+        if budgets.is_empty() {
+            return Ok(None);
+        }
+        match self.cache.consume_rate_budgets(&budgets)? {
+            None => Ok(None),
+            Some(retry_after) => match fallback {
+                Some(fallback) => {
+                    debug!("rate limited, using cached result from {:?} ago",
+                          fallback.1.elapsed().unwrap_or_default());
+                    Ok(Some(fallback))
+                },
+                None => Err(Error::new(RateLimited { retry_after })),
+            },
+        }
+    }
+
+    fn should_store(&self, result: &Invocation) -> bool {
+        !self.cache.read_only && (self.persist_failures || result.exit_code == 0)
+            && (!result.truncated || self.output_limit.map(|l| l.truncate).unwrap_or(false))
+    }
+
+    /// Updates the usage statistics recorded for the given command, if enabled. Stats are purely
+    /// informational, so failing to record them never fails the caller.
+    fn record_stats<F: FnOnce(&mut CommandStats)>(&self, command: &CommandDesc, update: F) {
+        if self.track_stats && !self.cache.read_only {
+            if let Err(e) = self.cache.update_stats(command, update) {
+                debug!("stats update failed: {:?}", e);
+            }
+        }
+    }
+
     /// Clean the cache in the background on a cache-miss; this will usually
     /// be much faster than the actual background process.
     fn maybe_cleanup_once(&self) -> Option<std::thread::JoinHandle<Result<()>>> {
@@ -873,7 +4240,7 @@ impl Bkt {
     fn join_cleanup_thread(cleanup_hook: Option<std::thread::JoinHandle<Result<()>>>) {
         if let Some(cleanup_hook) = cleanup_hook {
             if let Err(e) = cleanup_hook.join().expect("cleanup thread panicked") {
-                eprintln!("bkt: cache cleanup failed: {:?}", e);
+                warn!("cache cleanup failed: {:?}", e);
             }
         }
     }
@@ -890,23 +4257,44 @@ impl Bkt {
     /// cleaning up. It should be Ok in all normal circumstances.
     // TODO if cleanup should always succeed (or no-op) why return Result?
     pub fn cleanup_once(&self) -> std::thread::JoinHandle<Result<()>> {
-        let cache = self.cache.clone();
-        std::thread::spawn(move || { cache.cleanup() })
+        let tiers: Vec<_> = self.tiers().cloned().collect();
+        let policy = self.cleanup_policy.clone();
+        std::thread::spawn(move || { tiers.iter().try_for_each(|cache| cache.cleanup(&policy).map(|_| ())) })
+    }
+
+    /// Synchronously removes all stale data from the cache, regardless of the cleanup policy's
+    /// throttle, and reports how much was removed.
+    ///
+    /// # Errors
+    ///
+    /// If another process is cleaning up the cache at the same time, or cleaning up fails.
+    pub fn gc(&self) -> Result<CleanupReport> {
+        let policy = self.cleanup_policy.clone().throttle(Duration::ZERO);
+        let mut total = CleanupReport::default();
+        for cache in self.tiers() {
+            cache.ensure_writable()?;
+            let report = cache.cleanup(&policy)?.ok_or_else(|| Error::msg(format!(
+                "Cache {} is already being cleaned up", cache.cache_dir.display())))?;
+            total.files += report.files;
+            total.bytes += report.bytes;
+        }
+        Ok(total)
     }
 
     /// Initiates an infinite-loop thread that triggers periodic cleanups of the cache, removing
     /// stale data in the background. It is not necessary to `join()` this thread, it will
     /// be terminated when the main thread exits.
     pub fn cleanup_thread(&self) -> std::thread::JoinHandle<()> {
-        let cache = self.cache.clone();
+        let tiers: Vec<_> = self.tiers().cloned().collect();
+        let policy = self.cleanup_policy.clone();
         std::thread::spawn(move || {
-            //  Hard-coded for now, could be made configurable if needed
-            let poll_duration = Duration::from_secs(60);
             loop {
-                if let Err(e) = cache.cleanup() {
-                    eprintln!("Bkt: cache cleanup failed: {:?}", e);
+                for cache in &tiers {
+                    if let Err(e) = cache.cleanup(&policy) {
+                        warn!("cache cleanup failed: {:?}", e);
+                    }
                 }
-                std::thread::sleep(poll_duration);
+                std::thread::sleep(policy.poll_interval);
             }
         })
     }
@@ -943,6 +4331,502 @@ mod bkt_tests {
         }
     }
 
+    #[test]
+    fn retries_runtime_excludes_backoff() {
+        let dir = TestDir::temp();
+        let cmd = CommandDesc::new(["bash", "-c", "exit 3"]);
+        let policy = RetryPolicy::default().backoff(Duration::from_secs(2)).retries(1);
+        let bkt = Bkt::create(dir.path("cache")).unwrap().retry_policy(policy);
+        let start = Instant::now();
+        let result = bkt.refresh(&cmd, Duration::from_secs(10)).unwrap();
+        assert!(start.elapsed() >= Duration::from_secs(2));
+        assert_eq!(result.attempts(), 2);
+        assert!(result.runtime() < Duration::from_secs(1), "runtime shouldn't include the backoff: {:?}", result.runtime());
+    }
+
+    #[test]
+    fn streamed_equals_cached() {
+        let dir = TestDir::temp();
+        let cmd = CommandDesc::new(["echo", "streamed"]);
+        let bkt = Bkt::create(dir.path("cache")).unwrap().stream_output(true);
+        let (streamed, _) = bkt.retrieve(&cmd, Duration::from_secs(10)).unwrap();
+        let (cached, _) = bkt.retrieve(&cmd, Duration::from_secs(10)).unwrap();
+        assert!(streamed.streamed());
+        assert!(!cached.streamed());
+        assert_eq!(streamed, cached);
+    }
+
+    #[test]
+    fn retries() {
+        let dir = TestDir::temp();
+        let file = dir.path("file");
+        // Fails with exit code 3 until the third attempt
+        let cmd = CommandDesc::new(["bash", "-c", r#"echo >> "${1:?}"; n=$(wc -l < "${1:?}"); echo "$n"; (( n >= 3 )) || exit 3"#,
+            "arg0", file.to_str().unwrap()]);
+        let ttl = Duration::from_secs(10);
+        let policy = RetryPolicy::default().backoff(Duration::from_millis(10));
+
+        let bkt = Bkt::create(dir.path("cache")).unwrap().retry_policy(policy.clone().retries(1));
+        let result = bkt.refresh(&cmd, ttl).unwrap();
+        assert_eq!((result.exit_code(), result.attempts()), (3, 2));
+
+        std::fs::remove_file(&file).unwrap();
+        let bkt = bkt.retry_policy(policy.clone().retries(5).retry_on([1, 2]));
+        let result = bkt.refresh(&cmd, ttl).unwrap();
+        assert_eq!((result.exit_code(), result.attempts()), (3, 1));
+
+        std::fs::remove_file(&file).unwrap();
+        let bkt = bkt.retry_policy(policy.retries(5).retry_on([3]));
+        let result = bkt.refresh(&cmd, ttl).unwrap();
+        assert_eq!((result.exit_code(), result.attempts(), result.stdout_utf8()), (0, 3, "3\n"));
+        let (cached, _) = bkt.retrieve(&cmd, ttl).unwrap();
+        assert_eq!(cached, result);
+    }
+
+    #[test]
+    fn output_limit() {
+        let dir = TestDir::temp();
+        let file = dir.path("file");
+        let cmd = CommandDesc::new(["bash", "-c", r#"echo >> "${1:?}"; printf 'abcdef\nghij\n'"#,
+            "arg0", file.to_str().unwrap()]);
+        let runs = || std::fs::read_to_string(&file).unwrap().len();
+        let ttl = Duration::from_secs(10);
+
+        let bkt = Bkt::create(dir.path("cache")).unwrap().output_limit(OutputLimit::new(9));
+        let (result, _) = bkt.retrieve(&cmd, ttl).unwrap();
+        assert!(result.truncated());
+        assert_eq!(result.stdout_utf8(), "abcdef\ngh");
+        bkt.retrieve(&cmd, ttl).unwrap();
+        assert_eq!(runs(), 2, "truncated output should not be cached");
+
+        let bkt = bkt.output_limit(OutputLimit::new(9).truncate(true));
+        let (result, _) = bkt.retrieve(&cmd, ttl).unwrap();
+        assert_eq!(result.stdout_utf8(), "abcdef\ngh\n[bkt: output truncated after 9 bytes]\n");
+        let (cached, _) = bkt.retrieve(&cmd, ttl).unwrap();
+        assert_eq!(runs(), 3);
+        assert_eq!(cached, result);
+
+        let bkt = bkt.output_limit(OutputLimit::new(1024));
+        let result = bkt.refresh(&cmd, ttl).unwrap();
+        assert!(!result.truncated());
+        assert_eq!(result.stdout_utf8(), "abcdef\nghij\n");
+    }
+
+    #[test]
+    fn record_interleaving() {
+        let dir = TestDir::temp();
+        let cmd = CommandDesc::new(["bash", "-c", "echo out1; sleep .1; echo err >&2; sleep .1; echo out2"]);
+        let ttl = Duration::from_secs(10);
+
+        let bkt = Bkt::create(dir.path("cache")).unwrap();
+        let result = bkt.refresh(&cmd, ttl).unwrap();
+        assert!(!result.interleaved());
+
+        let bkt = bkt.record_interleaving(true);
+        let result = bkt.refresh(&cmd, ttl).unwrap();
+        let streams: Vec<_> = result.chunks.as_ref().unwrap().iter().map(|c| c.stream).collect();
+        assert_eq!(streams, [Stream::Stdout, Stream::Stderr, Stream::Stdout]);
+        let (cached, _) = bkt.retrieve(&cmd, ttl).unwrap();
+        assert_eq!(cached, result);
+
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        let start = Instant::now();
+        cached.replay(&mut out, &mut err, true).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert_eq!((out.as_slice(), err.as_slice()), (&b"out1\nout2\n"[..], &b"err\n"[..]));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn pty() {
+        let dir = TestDir::temp();
+        let script = "[[ -t 1 ]] && echo tty || echo pipe; [[ -t 2 ]] && echo tty >&2 || echo pipe >&2; seq 10000";
+        let cmd = CommandDesc::new(["bash", "-c", script]);
+        let ttl = Duration::from_secs(10);
+        let seq: String = (1..=10000).map(|n| format!("{}\n", n)).collect();
+
+        let bkt = Bkt::create(dir.path("cache")).unwrap();
+        let result = bkt.refresh(&cmd, ttl).unwrap();
+        assert_eq!((result.stdout_utf8(), result.stderr_utf8()), (&*format!("pipe\n{}", seq), "pipe\n"));
+
+        let pty_cmd = cmd.clone().with_pty();
+        assert_ne!(pty_cmd.cache_key(), cmd.cache_key());
+        let result = bkt.refresh(&pty_cmd, ttl).unwrap();
+        assert_eq!((result.stdout_utf8(), result.stderr_utf8()), (&*format!("tty\n{}", seq), "pipe\n"));
+        let (cached, _) = bkt.retrieve(&pty_cmd, ttl).unwrap();
+        assert_eq!(cached, result);
+    }
+
+    #[test]
+    fn retrieve_all() {
+        let dir = TestDir::temp();
+        let ttl = Duration::from_secs(10);
+        let mut commands: Vec<_> = (0..4)
+            .map(|i| CommandDesc::new(["bash", "-c", &format!("sleep .5; echo {}; date +%s%N", i)]))
+            .collect();
+        commands.push(CommandDesc::new(["/bkt/does/not/exist"]));
+        let bkt = Bkt::create(dir.path("cache")).unwrap();
+
+        let start = Instant::now();
+        let results = bkt.retrieve_all(&commands, ttl, 4);
+        assert!(start.elapsed() < Duration::from_millis(1500), "commands should run concurrently");
+        assert_eq!(results.len(), 5);
+        assert!(results[4].is_err());
+        let results: Vec<_> = results.into_iter().take(4).map(|r| r.unwrap()).collect();
+        for (i, (result, age)) in results.iter().enumerate() {
+            assert!(result.stdout_utf8().starts_with(&format!("{}\n", i)));
+            assert_eq!(*age, Duration::default());
+        }
+
+        let start = Instant::now();
+        let cached = bkt.retrieve_all(&commands[..4], ttl, 1);
+        assert!(start.elapsed() < Duration::from_millis(500), "results should be cached");
+        for (result, cached) in results.iter().zip(cached) {
+            assert_eq!(cached.unwrap().0, result.0);
+        }
+    }
+
+    #[test]
+    fn refresh_if_changed() {
+        let dir = TestDir::temp();
+        let (file, version) = (dir.path("file"), dir.path("version"));
+        let cmd = CommandDesc::new(["bash", "-c", r#"printf . >> "${1:?}"; cat "${1:?}""#, "arg0", file.to_str().unwrap()]);
+        let probe = CommandDesc::new(["cat", version.to_str().unwrap()]);
+        let ttl = Duration::from_secs(10);
+        let bkt = Bkt::create(dir.path("cache")).unwrap();
+        std::fs::write(&version, "1").unwrap();
+
+        let (result, _) = bkt.retrieve_validated(&cmd, &probe, ttl).unwrap();
+        assert_eq!(result.stdout_utf8(), ".");
+        let (cached, _) = bkt.retrieve_validated(&cmd, &probe, ttl).unwrap();
+        assert_eq!(cached, result);
+        let (result, executed) = bkt.refresh_if_changed(&cmd, &probe, ttl).unwrap();
+        assert_eq!((result.stdout_utf8(), executed), (".", false));
+        // revalidating isn't a hit; only the second retrieve_validated() call was, and the first
+        // was a miss rather than a refresh since nothing was cached yet
+        let stats = bkt.stats().unwrap();
+        let stats = &stats.iter().find(|(c, _)| c == &cmd).unwrap().1;
+        assert_eq!((stats.hits(), stats.misses(), stats.refreshes()), (1, 1, 0));
+        let (_, age) = bkt.retrieve(&cmd, ttl).unwrap();
+        assert!(age < Duration::from_secs(1));
+
+        std::fs::write(&version, "2").unwrap();
+        let (result, executed) = bkt.refresh_if_changed(&cmd, &probe, ttl).unwrap();
+        assert_eq!((result.stdout_utf8(), executed), ("..", true));
+        let stats = bkt.stats().unwrap();
+        assert_eq!(stats.iter().find(|(c, _)| c == &cmd).unwrap().1.refreshes(), 1);
+        let (cached, _) = bkt.retrieve(&cmd, ttl).unwrap();
+        assert_eq!(cached, result);
+
+        // a failing probe can't show the command is unchanged
+        std::fs::remove_file(&version).unwrap();
+        let (result, executed) = bkt.refresh_if_changed(&cmd, &probe, ttl).unwrap();
+        assert_eq!((result.stdout_utf8(), executed), ("...", true));
+        let (result, executed) = bkt.refresh_if_changed(&cmd, &probe, ttl).unwrap();
+        assert_eq!((result.stdout_utf8(), executed), ("....", true));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn watch() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        let dir = TestDir::temp();
+        let (file, config) = (dir.path("file"), dir.path("config"));
+        let read = |path: &Path| CommandDesc::new(
+            ["bash", "-c", r#"printf . >> "${1:?}"; cat "${1:?}""#, "arg0", path.to_str().unwrap()]);
+        let (invalidated, refreshed) = (read(&dir.path("invalidated")), read(&dir.path("refreshed")));
+        let ttl = Duration::from_secs(60);
+        let bkt = Bkt::create(dir.path("cache")).unwrap();
+        std::fs::write(&file, "1").unwrap();
+        std::fs::create_dir(&config).unwrap();
+
+        bkt.retrieve(&invalidated, ttl).unwrap();
+        bkt.register_watch(&invalidated, [&file], ttl, OnChange::Invalidate).unwrap();
+        let secret = CommandDesc::new(["true"]).with_secret_env_value("TOKEN", "hunter2");
+        assert!(bkt.register_watch(&secret, [&file], ttl, OnChange::Refresh).is_err());
+
+        // polls until the watcher has had a chance to act
+        let wait_for = |check: &dyn Fn() -> bool| {
+            let start = Instant::now();
+            while !check() {
+                assert!(start.elapsed() < Duration::from_secs(10), "watcher didn't react");
+                std::thread::sleep(Duration::from_millis(50));
+            }
+        };
+        let stop = AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            let watcher = scope.spawn(|| bkt.watch(&stop));
+            std::thread::sleep(Duration::from_millis(500));
+            std::fs::write(&file, "2").unwrap();
+            wait_for(&|| bkt.retrieve(&invalidated, ttl).unwrap().0.stdout_utf8() == "..");
+
+            // registrations made while watching are picked up
+            bkt.retrieve(&refreshed, ttl).unwrap();
+            bkt.register_watch(&refreshed, [&config], ttl, OnChange::Refresh).unwrap();
+            std::thread::sleep(Duration::from_millis(500));
+            std::fs::write(config.join("new"), "").unwrap();
+            wait_for(&|| std::fs::read_to_string(dir.path("refreshed")).unwrap() == "..");
+            wait_for(&|| bkt.retrieve(&refreshed, ttl).unwrap().0.stdout_utf8() == "..");
+
+            stop.store(true, Ordering::SeqCst);
+            watcher.join().unwrap().unwrap();
+        });
+    }
+
+    #[test]
+    fn parse_rate_limit() {
+        assert_eq!("1/10s".parse::<RateLimit>().unwrap(), RateLimit::new(1, Duration::from_secs(10)));
+        assert_eq!("5 / 2m".parse::<RateLimit>().unwrap(), RateLimit::new(5, Duration::from_secs(120)));
+        assert_eq!(RateLimit::new(5, Duration::from_secs(120)).to_string(), "5/2m");
+        for invalid in ["1", "0/10s", "1/0s", "x/10s", "1/x"] {
+            assert!(invalid.parse::<RateLimit>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn parse_schedule() {
+        assert_eq!("midnight".parse::<Schedule>().unwrap(), "0 0 * * *".parse().unwrap());
+        assert_eq!("6:30".parse::<Schedule>().unwrap(), "30 6 * * *".parse().unwrap());
+        assert_eq!("0 0 * * 7".parse::<Schedule>().unwrap(), "weekly".parse().unwrap());
+        assert_eq!("*/20 * * * *".parse::<Schedule>().unwrap(), "0,20,40 * * * *".parse().unwrap());
+        assert_eq!("0 9-17/4 * * *".parse::<Schedule>().unwrap(), "0 9,13,17 * * *".parse().unwrap());
+        for invalid in ["", "noon", "24:00", "0 0 * *", "60 * * * *", "0 0 0 * *", "0 5-1 * * *", "*/0 * * * *"] {
+            assert!(invalid.parse::<Schedule>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn schedule_boundaries() {
+        let to_secs = |t: SystemTime| t.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() as i64;
+        let now = SystemTime::now();
+        for (schedule, check) in [
+            ("hourly", (|tm: &libc::tm| tm.tm_min == 0) as fn(&libc::tm) -> bool),
+            ("midnight", |tm| (tm.tm_hour, tm.tm_min) == (0, 0)),
+            ("15 */6 * * 1-5", |tm| tm.tm_min == 15 && tm.tm_hour % 6 == 0 && (1..=5).contains(&tm.tm_wday)),
+            ("0 0 29 2 *", |tm| (tm.tm_mon, tm.tm_mday, tm.tm_hour, tm.tm_min) == (1, 29, 0, 0)),
+            ("0 12 13 * 5", |tm| tm.tm_hour == 12 && tm.tm_min == 0 && (tm.tm_mday == 13 || tm.tm_wday == 5)),
+        ] {
+            let schedule: Schedule = schedule.parse().unwrap();
+            let (previous, next) = (schedule.previous(now).unwrap(), schedule.next(now).unwrap());
+            assert!(previous <= now && now < next, "{:?}", schedule);
+            for boundary in [previous, next] {
+                let tm = local_time(to_secs(boundary));
+                assert!(check(&tm) && tm.tm_sec == 0, "{:?} {:?}", schedule, boundary);
+                assert_eq!(schedule.previous(boundary).unwrap(), boundary);
+            }
+            assert_eq!(schedule.next(previous).unwrap(), next, "{:?}", schedule);
+        }
+        assert!("0 0 30 2 *".parse::<Schedule>().unwrap().previous(now).is_err());
+    }
+
+    #[test]
+    fn rate_limit() {
+        let dir = TestDir::temp();
+        let ttl = Duration::from_secs(10);
+        let counter = |name: &str| {
+            let file = dir.path(name);
+            CommandDesc::new(["bash", "-c", r#"echo >> "${1:?}"; wc -l < "${1:?}""#, "arg0", file.to_str().unwrap()])
+        };
+        let bkt = Bkt::create(dir.path("cache")).unwrap().rate_limit("1/1h".parse().unwrap());
+
+        let cmd = counter("a");
+        assert_eq!(bkt.retrieve(&cmd, ttl).unwrap().0.stdout_utf8(), "1\n");
+        // the last result is used instead of executing again
+        assert_eq!(bkt.refresh(&cmd, ttl).unwrap().stdout_utf8(), "1\n");
+        // other commands have their own budget
+        assert_eq!(bkt.retrieve(&counter("b"), ttl).unwrap().0.stdout_utf8(), "1\n");
+
+        // failures aren't cached, so there's nothing to fall back to
+        let failing = CommandDesc::new(["bash", "-c", "false"]);
+        let bkt = bkt.discard_failures(true);
+        assert_eq!(bkt.retrieve(&failing, ttl).unwrap().0.exit_code(), 1);
+        let err = bkt.retrieve(&failing, ttl).unwrap_err();
+        assert!(err.downcast_ref::<RateLimited>().unwrap().retry_after() > Duration::from_secs(3500));
+
+        // corrupt budgets are treated as unused, rather than failing forever
+        for entry in std::fs::read_dir(bkt.cache.rate_dir()).unwrap() {
+            std::fs::write(entry.unwrap().path().join("executions"), "corrupt").unwrap();
+        }
+        assert_eq!(bkt.retrieve(&failing, ttl).unwrap().0.exit_code(), 1);
+        assert!(bkt.retrieve(&failing, ttl).is_err());
+    }
+
+    #[test]
+    fn tag_rate_limit() {
+        let dir = TestDir::temp();
+        let ttl = Duration::from_secs(10);
+        let bkt = Bkt::create(dir.path("cache")).unwrap()
+            .tagged("api".into()).tag_rate_limit("1/1h".parse().unwrap());
+
+        bkt.retrieve(&CommandDesc::new(["echo", "a"]), ttl).unwrap();
+        let err = bkt.retrieve(&CommandDesc::new(["echo", "b"]), ttl).unwrap_err();
+        assert!(err.is::<RateLimited>());
+        // untagged commands aren't limited
+        let untagged = Bkt::create(dir.path("cache")).unwrap().tag_rate_limit("1/1h".parse().unwrap());
+        untagged.retrieve(&CommandDesc::new(["echo", "b"]), ttl).unwrap();
+    }
+
+    #[test]
+    fn shared_tier() {
+        let dir = TestDir::temp();
+        let file = dir.path("file");
+        let cmd = CommandDesc::new(
+            ["bash", "-c", r#"echo "$RANDOM" > "${1:?}"; cat "${1:?}""#, "arg0", file.to_str().unwrap()]);
+        let ttl = Duration::from_secs(10);
+        let bkt_a = Bkt::create(dir.path("a")).unwrap().shared_tier(dir.path("shared"), None).unwrap();
+        let bkt_b = Bkt::create(dir.path("b")).unwrap().shared_tier(dir.path("shared"), None).unwrap();
+
+        // written through to both tiers
+        let (first_inv, _) = bkt_a.retrieve(&cmd, ttl).unwrap();
+        assert_eq!(Bkt::create(dir.path("a")).unwrap().retrieve(&cmd, ttl).unwrap().0, first_inv);
+        assert_eq!(Bkt::create(dir.path("shared")).unwrap().retrieve(&cmd, ttl).unwrap().0, first_inv);
+
+        // found in the shared tier and promoted to the local tier
+        assert_eq!(bkt_b.retrieve(&cmd, ttl).unwrap().0, first_inv);
+        assert_eq!(Bkt::create(dir.path("b")).unwrap().retrieve(&cmd, ttl).unwrap().0, first_inv);
+
+        // a shared tier that can't be written to doesn't prevent caching locally
+        let shared_data = bkt_a.shared_tiers[0].data_dir();
+        std::fs::remove_dir_all(&shared_data).unwrap();
+        std::fs::write(&shared_data, "").unwrap();
+        let refreshed = bkt_a.refresh(&cmd, ttl).unwrap();
+        assert_eq!(Bkt::create(dir.path("a")).unwrap().retrieve(&cmd, ttl).unwrap().0, refreshed);
+    }
+
+    #[test]
+    #[cfg(feature = "http")]
+    fn remote_cache() {
+        let dir = TestDir::temp();
+        let file = dir.path("file");
+        let cmd = CommandDesc::new(
+            ["bash", "-c", r#"echo "$RANDOM" > "${1:?}"; cat "${1:?}""#, "arg0", file.to_str().unwrap()]);
+        let ttl = Duration::from_secs(10);
+        let url = super::remote_cache_tests::serve();
+        let bkt_a = Bkt::create(dir.path("a")).unwrap().remote_cache(&url, Duration::from_secs(10));
+        let bkt_b = Bkt::create(dir.path("b")).unwrap().remote_cache(&url, Duration::from_secs(10));
+
+        let (first_inv, _) = bkt_a.retrieve(&cmd, ttl).unwrap();
+        assert_eq!(bkt_b.retrieve(&cmd, ttl).unwrap().0, first_inv);
+        // promoted to the local cache
+        assert_eq!(Bkt::create(dir.path("b")).unwrap().retrieve(&cmd, ttl).unwrap().0, first_inv);
+    }
+
+    #[test]
+    #[cfg(feature = "http")]
+    fn remote_cache_unavailable() {
+        let dir = TestDir::temp();
+        let file = dir.path("file");
+        let cmd = CommandDesc::new(
+            ["bash", "-c", r#"echo "$RANDOM" > "${1:?}"; cat "${1:?}""#, "arg0", file.to_str().unwrap()]);
+        let ttl = Duration::from_secs(10);
+        let url = format!("http://{}", std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap());
+        let bkt = Bkt::create(dir.path("cache")).unwrap().remote_cache(&url, Duration::from_secs(10));
+
+        let (first_inv, _) = bkt.retrieve(&cmd, ttl).unwrap();
+        assert_eq!(bkt.retrieve(&cmd, ttl).unwrap().0, first_inv);
+    }
+
+    #[test]
+    fn read_only() {
+        let dir = TestDir::temp();
+        let file = dir.path("file");
+        let cmd = CommandDesc::new(
+            ["bash", "-c", r#"echo "$RANDOM" > "${1:?}"; cat "${1:?}""#, "arg0", file.to_str().unwrap()]);
+        let bkt = Bkt::read_only(dir.path("cache"));
+        bkt.retrieve(&cmd, Duration::from_secs(10)).unwrap();
+        assert!(!dir.path("cache").exists());
+
+        // nothing is cached, so the command runs again
+        std::fs::write(&file, "").unwrap();
+        let (subsequent_inv, _) = bkt.retrieve(&cmd, Duration::from_secs(10)).unwrap();
+        assert_ne!(std::fs::read_to_string(&file).unwrap(), "");
+        assert_eq!(subsequent_inv.stdout_utf8(), std::fs::read_to_string(&file).unwrap());
+
+        // but data already in the cache is used
+        Bkt::create(dir.path("cache")).unwrap().retrieve(&cmd, Duration::from_secs(10)).unwrap();
+        let (cached_inv, _) = bkt.retrieve(&cmd, Duration::from_secs(10)).unwrap();
+        let (cached_again, _) = bkt.retrieve(&cmd, Duration::from_secs(10)).unwrap();
+        assert_eq!(cached_inv, cached_again);
+        assert!(bkt.invalidate_tag("tag").is_err());
+    }
+
+    #[test]
+    #[cfg(not(feature = "debug"))] // See with_env
+    fn with_isolated_env() {
+        let dir = TestDir::temp();
+        let cmd = CommandDesc::new(["bash", "-c", "env"]).with_isolated_env()
+            .with_env_value("FOO", "bar").with_secret_env_value("TOKEN", "baz");
+        let bkt = Bkt::create(dir.path("cache")).unwrap();
+        let (result, _) = bkt.retrieve(&cmd, Duration::from_secs(10)).unwrap();
+        let mut vars: Vec<_> = result.stdout_utf8().lines()
+            .map(|l| l.split('=').next().unwrap())
+            // bash sets a few variables of its own
+            .filter(|k| !["PWD", "SHLVL", "_"].contains(k))
+            .collect();
+        vars.sort_unstable();
+        let mut expected = vec!["FOO", "TOKEN"];
+        expected.extend(ISOLATED_ENV_ALLOWLIST.iter().filter(|k| std::env::var_os(k).is_some()));
+        expected.sort_unstable();
+        assert_eq!(vars, expected);
+    }
+
+    #[test]
+    #[cfg(not(feature = "debug"))] // See with_env
+    fn with_secret_env() {
+        fn files(dir: &Path) -> Vec<PathBuf> {
+            std::fs::read_dir(dir).unwrap().flat_map(|e| {
+                let path = e.unwrap().path();
+                if path.is_dir() { files(&path) } else { vec![path] }
+            }).collect()
+        }
+
+        let dir = TestDir::temp();
+        let cmd = CommandDesc::new(["bash", "-c", r#"echo "TOKEN:${#TOKEN}""#]);
+        let bkt = Bkt::create(dir.path("cache")).unwrap();
+        let (result, _) = bkt.retrieve(&cmd.clone().with_secret_env_value("TOKEN", "hunter2"),
+                                       Duration::from_secs(10)).unwrap();
+        assert_eq!(result.stdout_utf8(), "TOKEN:7\n");
+        let (_, age) = bkt.retrieve(&cmd.clone().with_secret_env_value("TOKEN", "hunter2"),
+                                    Duration::from_secs(10)).unwrap();
+        assert!(age > Duration::ZERO); // cached
+        let (result, _) = bkt.retrieve(&cmd.clone().with_secret_env_value("TOKEN", "letmein"),
+                                       Duration::from_secs(10)).unwrap();
+        assert_eq!(result.stdout_utf8(), "TOKEN:7\n");
+        assert_eq!(bkt.stats().unwrap().len(), 2); // cached separately
+
+        let cache_files = files(&dir.path("cache"));
+        assert!(!cache_files.is_empty());
+        for file in cache_files {
+            let contents = String::from_utf8_lossy(&std::fs::read(&file).unwrap()).to_string();
+            assert!(!contents.contains("hunter2"), "{} contains secret", file.display());
+            assert!(!contents.contains("letmein"), "{} contains secret", file.display());
+        }
+        assert!(!format!("{:?}", cmd.with_secret_env_value("TOKEN", "hunter2")).contains("hunter2"));
+    }
+
+    #[test]
+    fn stats() {
+        let dir = TestDir::temp();
+        let cmd = CommandDesc::new(["bash", "-c", "sleep .1; exit 2"]);
+        let bkt = Bkt::create(dir.path("cache")).unwrap();
+        bkt.retrieve(&cmd, Duration::from_secs(10)).unwrap();
+        let (cached, _) = bkt.retrieve(&cmd, Duration::from_secs(10)).unwrap();
+        bkt.retrieve(&cmd, Duration::from_secs(10)).unwrap();
+        bkt.refresh(&cmd, Duration::from_secs(10)).unwrap();
+
+        let stats = bkt.stats().unwrap();
+        assert_eq!(stats.len(), 1);
+        let (stats_cmd, stats) = &stats[0];
+        assert_eq!(stats_cmd, &cmd);
+        assert_eq!((stats.hits(), stats.misses(), stats.refreshes(), stats.failures()), (2, 1, 1, 2));
+        assert_eq!(stats.saved_runtime(), cached.runtime() * 2);
+
+        let untracked = bkt.clone().track_stats(false);
+        untracked.retrieve(&cmd, Duration::from_secs(10)).unwrap();
+        assert_eq!(untracked.stats().unwrap()[0].1.hits(), 2);
+    }
+
     #[test]
     fn discard_failures() {
         let dir = TestDir::temp();
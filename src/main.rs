@@ -1,18 +1,62 @@
-use std::collections::{BTreeMap, BTreeSet};
 use std::ffi::OsStr;
-use std::io::{self, Write};
-use std::path::PathBuf;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, IsTerminal, Write};
+use std::path::{Path, PathBuf};
 use std::process::{Command, exit, Stdio};
-use std::time::{Duration};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use anyhow::{Context, Result};
-use clap::{crate_description, crate_name, crate_version, value_t_or_exit, Arg, App};
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{crate_description, crate_name, crate_version, value_t_or_exit, values_t_or_exit, Arg, App};
+use log::{debug, LevelFilter, Log, Metadata, Record};
+use regex::Regex;
 
-use bkt::{CommandDesc, Bkt};
+use bkt::{CleanupPolicy, CommandDesc, Bkt, OnChange, OutputLimit, RateLimit, RetryPolicy, Schedule};
 
-// Re-invokes bkt with --force and then discards the subprocess, causing the cache
-// to be refreshed asynchronously.
+// Writes log records from bkt (the library and binary) to stderr.
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("bkt: [{}] {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+// Logs warnings by default; BKT_LOG=LEVEL and each --verbose flag can raise the level.
+fn init_logging(verbosity: u64) {
+    let mut level = LevelFilter::Warn;
+    if let Some(env_level) = std::env::var_os("BKT_LOG") {
+        match env_level.to_str().and_then(|l| l.parse().ok()) {
+            Some(env_level) => level = env_level,
+            None => eprintln!("bkt: ignoring invalid BKT_LOG level {:?}", env_level),
+        }
+    }
+    let verbose_level = match verbosity {
+        0 => LevelFilter::Off,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    log::set_max_level(level.max(verbose_level));
+    log::set_logger(&LOGGER).expect("Logger already set");
+}
+
+// Re-invokes bkt with --force (or the given flag) and then discards the subprocess, causing the
+// cache to be refreshed asynchronously.
 fn force_update_async() -> Result<()> {
+    update_async("--force")
+}
+
+fn update_async(flag: &str) -> Result<()> {
     let mut args = std::env::args_os();
     let arg0 = args.next().expect("Must always be a 0th argument");
     let mut command = match std::env::current_exe() {
@@ -22,41 +66,478 @@ fn force_update_async() -> Result<()> {
     // Discard stdout/err so the calling process doesn't wait for them to close.
     // Intentionally drop the returned Child; after this process exits the
     // child process will continue running in the background.
-    command.arg("--force").args(args.filter(|a| a != "--warm"))
-        .stdout(Stdio::null()).stderr(Stdio::null())
-        .spawn().context("Failed to start background process")?;
+    command.arg(flag).args(args.filter(|a| a != "--warm"))
+        .stdout(Stdio::null()).stderr(Stdio::null());
+    let child = command.spawn().context("Failed to start background process")?;
+    debug!("spawned background refresh (PID {})", child.id());
     Ok(())
 }
 
+// Where cached data is stored, and how it may be used
+#[derive(Clone)]
+struct CacheConfig<'a> {
+    root_dir: Option<PathBuf>,
+    max_ttl: Option<Duration>,
+    shared_dir: Option<PathBuf>,
+    shared_max_ttl: Option<Duration>,
+    #[cfg(feature = "http")]
+    remote_url: Option<&'a str>,
+    #[cfg(feature = "http")]
+    remote_timeout: Duration,
+    scope: Option<&'a str>,
+    read_only: bool,
+    cleanup_policy: CleanupPolicy,
+}
+
+impl CacheConfig<'_> {
+    fn create_bkt(&self) -> Result<Bkt> {
+        let mut bkt = match (self.root_dir.clone(), self.read_only) {
+            (Some(cache_dir), false) => Bkt::create(cache_dir)?,
+            (None, false) => Bkt::in_tmp()?,
+            (Some(cache_dir), true) => Bkt::read_only(cache_dir),
+            (None, true) => Bkt::read_only_in_tmp(),
+        };
+        if let Some(max_ttl) = self.max_ttl {
+            bkt = bkt.max_ttl(max_ttl);
+        }
+        if let Some(shared_dir) = &self.shared_dir {
+            bkt = bkt.shared_tier(shared_dir.clone(), self.shared_max_ttl)?;
+        }
+        #[cfg(feature = "http")]
+        if let Some(remote_url) = self.remote_url {
+            bkt = bkt.remote_cache(remote_url, self.remote_timeout);
+        }
+        if let Some(scope) = self.scope {
+            bkt = bkt.scoped(scope.into());
+        }
+        Ok(bkt.cleanup_policy(self.cleanup_policy.clone()))
+    }
+}
+
+// Cleans up the cache immediately and reports how much was removed
+fn gc(config: &CacheConfig) -> Result<i32> {
+    let report = config.create_bkt()?.gc()?;
+    println!("Removed {} files ({} bytes)", report.files_removed(), report.bytes_reclaimed());
+    Ok(0)
+}
+
+// Invalidates or refreshes cached results as the paths registered with --watch-path change, until
+// killed
+fn watch(config: &CacheConfig) -> Result<i32> {
+    let stop = AtomicBool::new(false);
+    config.create_bkt()?.watch(&stop)?;
+    Ok(0)
+}
+
+// Prints the recorded usage statistics, most valuable commands first
+fn print_stats(config: &CacheConfig) -> Result<i32> {
+    let bkt = config.create_bkt()?;
+    let mut stats = bkt.stats()?;
+    stats.sort_by_key(|(_, s)| std::cmp::Reverse(s.saved_runtime()));
+
+    let mut out = io::stdout();
+    writeln!(out, "{:>10}  {:>6}  {:>6}  {:>9}  {:>8}  COMMAND",
+             "SAVED", "HITS", "MISSES", "REFRESHES", "FAILURES")?;
+    for (command, stats) in stats {
+        let command = command.args().iter()
+            .map(|a| a.to_string_lossy()).collect::<Vec<_>>().join(" ");
+        writeln!(out, "{:>9.3}s  {:>6}  {:>6}  {:>9}  {:>8}  {}",
+                 stats.saved_runtime().as_secs_f64(), stats.hits(), stats.misses(),
+                 stats.refreshes(), stats.failures(), command)?;
+    }
+    Ok(0)
+}
+
+// Summary statistics over a set of timings
+struct Timings {
+    mean: Duration,
+    p50: Duration,
+    p95: Duration,
+}
+
+impl Timings {
+    fn new(mut samples: Vec<Duration>) -> Self {
+        assert!(!samples.is_empty());
+        samples.sort();
+        // nearest-rank percentiles
+        let percentile = |p: usize| samples[(samples.len() * p).div_ceil(100).max(1) - 1];
+        Timings {
+            mean: samples.iter().sum::<Duration>() / samples.len() as u32,
+            p50: percentile(50),
+            p95: percentile(95),
+        }
+    }
+}
+
+fn time_quiet(command: &mut Command) -> Result<Duration> {
+    let start = Instant::now();
+    command.stdout(Stdio::null()).stderr(Stdio::null()).status()
+        .context("Failed to run benchmark command")?;
+    Ok(start.elapsed())
+}
+
+// Measures the overhead bkt adds to the given command by comparing running the command directly to
+// invoking bkt (with all other flags the user passed) with a cold and a warm cache, using a
+// throwaway scope for each iteration so every iteration starts with a cache miss.
+fn benchmark(config: &CacheConfig, command: CommandDesc, iterations: u32) -> Result<i32> {
+    let bkt_exe = std::env::current_exe().context("Failed to locate bkt binary")?;
+    let scope_prefix = format!("benchmark-{}-{}", std::process::id(),
+                               SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs());
+    // Only drop bkt's own --benchmark flag, not arguments to the command after --
+    let all_args: Vec<_> = std::env::args_os().skip(1).collect();
+    let (flags, command_args) = all_args.split_at(all_args.iter().position(|a| a == "--").unwrap_or(all_args.len()));
+    let bkt_args: Vec<_> = flags.iter().filter(|a| !a.to_string_lossy().starts_with("--benchmark")).cloned()
+        .chain(command_args.iter().cloned()).collect();
+    let args = command.args();
+
+    let mut out = io::stdout();
+    writeln!(out, "Benchmarking:\n\t{}\n",
+             args.iter().map(|a| a.to_string_lossy()).collect::<Vec<_>>().join(" "))?;
+
+    let (mut raw, mut miss, mut hit) = (vec![], vec![], vec![]);
+    for i in 0..iterations {
+        raw.push(time_quiet(Command::new(&args[0]).args(&args[1..]))?);
+        let scope = format!("{}-{}", scope_prefix, i);
+        let scope_arg = format!("--scope={}", scope);
+        miss.push(time_quiet(Command::new(&bkt_exe).arg(&scope_arg).args(&bkt_args))?);
+        hit.push(time_quiet(Command::new(&bkt_exe).arg(&scope_arg).args(&bkt_args))?);
+        // Discard everything the throwaway scope left behind, including its stats
+        if !config.read_only {
+            if let Err(e) = (CacheConfig { scope: Some(&scope), ..config.clone() }).create_bkt()?.clear() {
+                debug!("failed to remove benchmark results: {:#}", e);
+            }
+        }
+    }
+    let (raw, miss, hit) = (Timings::new(raw), Timings::new(miss), Timings::new(hit));
+
+    writeln!(out, "Results over {} iteration(s):", iterations)?;
+    writeln!(out, "{:<12}{:>10}{:>10}{:>10}", "", "mean", "p50", "p95")?;
+    for (label, timings) in [("Original:", &raw), ("Cache Miss:", &miss), ("Cache Hit:", &hit)] {
+        writeln!(out, "{:<12}{:>9.4}s{:>9.4}s{:>9.4}s", label,
+                 timings.mean.as_secs_f64(), timings.p50.as_secs_f64(), timings.p95.as_secs_f64())?;
+    }
+
+    writeln!(out)?;
+    if hit.mean >= raw.mean {
+        writeln!(out, "Not recommended: cache hits are no faster than running the command directly. \
+                       Caching this command is not worthwhile with this cache directory.")?;
+    } else {
+        let speedup = raw.mean.as_secs_f64() / hit.mean.as_secs_f64();
+        let verdict = if speedup < 2.0 { "Marginal" } else { "Recommended" };
+        writeln!(out, "{}: cache hits are {:.1}x faster than running the command directly, \
+                       while cache misses add {:.4}s of overhead.",
+                 verdict, speedup, miss.mean.saturating_sub(raw.mean).as_secs_f64())?;
+    }
+    Ok(0)
+}
+
+// Expires all entries carrying any of the given tags
+fn invalidate_tags(config: &CacheConfig, tags: Vec<&str>) -> Result<i32> {
+    let bkt = config.create_bkt()?;
+    for tag in tags {
+        let count = bkt.invalidate_tag(tag)?;
+        debug!("invalidated {} entries tagged {}", count, tag);
+    }
+    Ok(0)
+}
+
+// The environment variables to include in the cache key
+struct EnvKeys<'a> {
+    patterns: Vec<&'a OsStr>,
+    regexes: Vec<Regex>,
+    all_except: Option<Vec<&'a str>>,
+    secrets: Vec<&'a OsStr>,
+    isolated: bool,
+}
+
+impl EnvKeys<'_> {
+    fn apply(&self, mut command: CommandDesc) -> CommandDesc {
+        if self.isolated {
+            command = command.with_isolated_env();
+        }
+        for pattern in &self.patterns {
+            command = match pattern.to_str() {
+                Some(glob) if glob.contains(['*', '?']) => command.with_envs_matching(glob),
+                _ => command.with_env(pattern),
+            };
+        }
+        if !self.regexes.is_empty() {
+            command = command.with_envs_where(|k|
+                k.to_str().map(|k| self.regexes.iter().any(|r| r.is_match(k))).unwrap_or(false));
+        }
+        if let Some(excluding) = &self.all_except {
+            command = command.with_envs_excluding(excluding);
+        }
+        for secret in &self.secrets {
+            command = command.with_secret_env(secret);
+        }
+        command
+    }
+}
+
+fn export(config: &CacheConfig, file: &OsStr) -> Result<i32> {
+    let bkt = config.create_bkt()?;
+    let mut writer = BufWriter::new(File::create(file)
+        .with_context(|| format!("Failed to create {}", Path::new(file).display()))?);
+    let count = bkt.export(&mut writer)?;
+    writer.flush()?;
+    debug!("exported {} entries to {}", count, Path::new(file).display());
+    Ok(0)
+}
+
+fn import(config: &CacheConfig, file: &OsStr, rebase: bool) -> Result<i32> {
+    let bkt = config.create_bkt()?;
+    let reader = BufReader::new(File::open(file)
+        .with_context(|| format!("Failed to open {}", Path::new(file).display()))?);
+    let count = bkt.import(reader, rebase)?;
+    debug!("imported {} entries from {}", count, Path::new(file).display());
+    Ok(0)
+}
+
+// A command to refresh, read from a --warm-from manifest
+struct WarmEntry {
+    command: CommandDesc,
+    ttl: Option<Duration>,
+    scope: Option<String>,
+    tags: Vec<String>,
+}
+
+// Parses a --warm-from manifest, which lists one command per line, optionally preceded by
+// per-command options and a -- separator. Blank lines and lines starting with # are ignored.
+fn parse_manifest(file: &OsStr) -> Result<Vec<WarmEntry>> {
+    let contents = std::fs::read_to_string(file)
+        .with_context(|| format!("Failed to read {}", Path::new(file).display()))?;
+    contents.lines().enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+        .map(|(n, line)| parse_manifest_line(line)
+            .with_context(|| format!("Invalid entry on line {} of {}", n + 1, Path::new(file).display())))
+        .collect()
+}
+
+fn parse_manifest_line(line: &str) -> Result<WarmEntry> {
+    let words = shlex::split(line).ok_or_else(|| anyhow!("Unbalanced quotes"))?;
+    let (options, command) = match words.iter().position(|w| w == "--") {
+        Some(i) => (&words[..i], &words[i + 1..]),
+        None => (&[][..], &words[..]),
+    };
+    if command.is_empty() {
+        bail!("No command given");
+    }
+    // Most likely options without the -- separating them from the command
+    if command[0].starts_with("--") {
+        bail!("Options must be followed by --, found '{}'", command[0]);
+    }
+    let mut entry = WarmEntry { command: CommandDesc::new(command), ttl: None, scope: None, tags: vec![] };
+    for option in options {
+        let (name, value) = option.split_once('=')
+            .ok_or_else(|| anyhow!("Expected an option of the form --NAME=VALUE, found '{}'", option))?;
+        match name {
+            "--ttl" | "--time-to-live" => entry.ttl = Some(value.parse::<humantime::Duration>()?.into()),
+            "--scope" => entry.scope = Some(value.into()),
+            "--tag" => entry.tags.push(value.into()),
+            "--cwd" => entry.command = entry.command.with_working_dir(value),
+            "--env" => entry.command = entry.command.with_env(value),
+            _ => bail!("Unsupported option '{}'", name),
+        }
+    }
+    Ok(entry)
+}
+
+// Refreshes every command in a --warm-from manifest, up to `jobs` at a time
+fn warm_from(config: &CacheConfig, file: &OsStr, ttl: Duration, jobs: usize, force: bool) -> Result<i32> {
+    // Parse the manifest before backgrounding so that errors are reported
+    let entries = parse_manifest(file)?;
+    if !force {
+        force_update_async()?;
+        return Ok(0);
+    }
+
+    // Clean up once for the whole batch, rather than on each refresh. Lines' own scopes take
+    // precedence over --scope, so the scope is applied per line.
+    let bkt = CacheConfig { scope: None, ..config.clone() }.create_bkt()?.cleanup_on_refresh(false);
+    let cleanup = bkt.cleanup_once();
+    let next = AtomicUsize::new(0);
+    let failures = AtomicUsize::new(0);
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.max(1).min(entries.len()) {
+            scope.spawn(|| {
+                while let Some(entry) = entries.get(next.fetch_add(1, Ordering::SeqCst)) {
+                    let mut bkt = bkt.clone();
+                    if let Some(scope) = entry.scope.as_deref().or(config.scope) {
+                        bkt = bkt.scoped(scope.into());
+                    }
+                    for tag in &entry.tags {
+                        bkt = bkt.tagged(tag.clone());
+                    }
+                    if let Err(e) = bkt.refresh(&entry.command, entry.ttl.unwrap_or(ttl)) {
+                        let command = entry.command.args().iter()
+                            .map(|a| a.to_string_lossy()).collect::<Vec<_>>().join(" ");
+                        eprintln!("bkt: failed to warm '{}': {:#}", command, e);
+                        failures.fetch_add(1, Ordering::SeqCst);
+                    }
+                }
+            });
+        }
+    });
+    if let Err(e) = cleanup.join().expect("cleanup thread panicked") {
+        debug!("cache cleanup failed: {:#}", e);
+    }
+    debug!("warmed {} commands from {}", entries.len(), Path::new(file).display());
+    Ok(if failures.into_inner() == 0 { 0 } else { 1 })
+}
+
+// The length of the schedule's current period, used as the TTL when --expire-at is set without --ttl
+fn schedule_period(schedule: &Schedule) -> Result<Duration> {
+    let now = SystemTime::now();
+    Ok(schedule.next(now)?.duration_since(schedule.previous(now)?)?)
+}
+
+// Tags are used as file names in the cache directory
+fn validate_tag(tag: String) -> std::result::Result<(), String> {
+    if tag.is_empty() || tag == "." || tag == ".." || tag.contains(std::path::is_separator) {
+        return Err(format!("'{}' is not a valid tag; tags must be non-empty file names", tag));
+    }
+    Ok(())
+}
+
+// Parses a byte count such as 512, 64K or 1.5M (binary multiples)
+fn parse_size(size: &str) -> std::result::Result<u64, String> {
+    let size = size.trim();
+    let (digits, multiplier) = match size.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => {
+            let multiplier: u64 = match c.to_ascii_uppercase() {
+                'B' => 1,
+                'K' => 1 << 10,
+                'M' => 1 << 20,
+                'G' => 1 << 30,
+                _ => return Err(format!("Unknown size suffix in '{}'", size)),
+            };
+            (&size[..i], multiplier)
+        },
+        _ => (size, 1),
+    };
+    let value: f64 = digits.trim().parse().map_err(|_| format!("Invalid size '{}'", size))?;
+    if !value.is_finite() || value < 0.0 {
+        return Err(format!("Invalid size '{}'", size));
+    }
+    Ok((value * multiplier as f64) as u64)
+}
+
+// How the command's output is captured and written
+struct OutputOptions {
+    limit: Option<OutputLimit>,
+    stream: bool,
+    interleave: bool,
+    replay_timing: bool,
+    color: Color,
+}
+
+// Whether ANSI escape sequences (colors, etc.) in the command's output are written
+#[derive(Clone, Copy)]
+enum Color { Always, Auto, Never }
+
+impl Color {
+    fn wrap<W: Write + IsTerminal>(self, out: W) -> StripAnsi<W> {
+        let strip = match self {
+            Color::Always => false,
+            Color::Auto => !out.is_terminal(),
+            Color::Never => true,
+        };
+        StripAnsi { inner: out, strip, state: AnsiState::Text }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum AnsiState { Text, Escape, Intermediate, Csi, Osc, OscEscape }
+
+// Removes ANSI escape sequences from the data written through it, if enabled. Tracks state across
+// writes so that sequences split between writes are still removed.
+struct StripAnsi<W> {
+    inner: W,
+    strip: bool,
+    state: AnsiState,
+}
+
+impl<W: Write> Write for StripAnsi<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.strip {
+            return self.inner.write(buf);
+        }
+        let mut text = Vec::with_capacity(buf.len());
+        for &b in buf {
+            self.state = match (self.state, b) {
+                (_, 0x1B) if self.state != AnsiState::Osc => AnsiState::Escape,
+                (AnsiState::Text, _) => { text.push(b); AnsiState::Text },
+                (AnsiState::Escape, b'[') => AnsiState::Csi,
+                (AnsiState::Escape, b']') => AnsiState::Osc,
+                (AnsiState::Escape, 0x20..=0x2F) | (AnsiState::Intermediate, 0x20..=0x2F) => AnsiState::Intermediate,
+                (AnsiState::Escape, _) | (AnsiState::Intermediate, _) => AnsiState::Text,
+                (AnsiState::Csi, 0x40..=0x7E) => AnsiState::Text,
+                (AnsiState::Csi, _) => AnsiState::Csi,
+                // Operating system commands end with BEL or ESC \
+                (AnsiState::Osc, 0x07) => AnsiState::Text,
+                (AnsiState::Osc, 0x1B) => AnsiState::OscEscape,
+                (AnsiState::Osc, _) => AnsiState::Osc,
+                (AnsiState::OscEscape, _) => AnsiState::Text,
+            };
+        }
+        self.inner.write_all(&text)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> { self.inner.flush() }
+}
+
 // Runs bkt after main() handles flag parsing
 #[allow(clippy::too_many_arguments)]
-fn run(root_dir: Option<PathBuf>, discard_failures: bool, scope: Option<&str>,
-       mut command: CommandDesc, use_cwd: bool, env_keys: BTreeSet<&OsStr>, ttl: Duration,
-       stale: Option<Duration>, warm: bool, force: bool) -> Result<i32> {
+fn run(config: &CacheConfig, discard_failures: bool, retry_policy: RetryPolicy,
+       max_rate: Option<RateLimit>, max_tag_rate: Option<RateLimit>, output: OutputOptions,
+       tags: Vec<&str>, watch_paths: Vec<&OsStr>, on_change: OnChange,
+       mut command: CommandDesc, use_cwd: bool, key_on_binary: bool, git_state: bool, git_dirty: bool,
+       pty: bool, env_keys: EnvKeys, expire_at: Option<Schedule>, ttl: Duration,
+       stale: Option<Duration>, mut validate_with: Option<CommandDesc>, revalidate: bool, warm: bool,
+       force: bool) -> Result<i32> {
     assert!(!ttl.as_secs() > 0 || ttl.subsec_nanos() > 0, "--ttl cannot be zero"); // TODO use is_zero once stable
     if let Some(stale) = stale {
         assert!(!stale.as_secs() > 0 || stale.subsec_nanos() > 0, "--stale cannot be zero"); // TODO use is_zero once stable
         assert!(stale < ttl, "--stale must be less than --ttl");
     }
 
-    let mut bkt = match root_dir {
-        Some(cache_dir) => Bkt::create(cache_dir)?,
-        None => Bkt::in_tmp()?,
-    };
-    bkt = bkt.discard_failures(discard_failures);
-    if let Some(scope) = scope {
-        bkt = bkt.scoped(scope.into());
+    let mut bkt = config.create_bkt()?.discard_failures(discard_failures).retry_policy(retry_policy);
+    for tag in tags {
+        bkt = bkt.tagged(tag.into());
+    }
+    if let Some(max_rate) = max_rate {
+        bkt = bkt.rate_limit(max_rate);
+    }
+    if let Some(max_tag_rate) = max_tag_rate {
+        bkt = bkt.tag_rate_limit(max_tag_rate);
+    }
+    if let Some(output_limit) = output.limit {
+        bkt = bkt.output_limit(output_limit);
     }
+    bkt = bkt.stream_output(output.stream).record_interleaving(output.interleave);
 
     if use_cwd {
         command = command.with_cwd()?;
+        // The probe should inspect the same directory as the command
+        validate_with = validate_with.map(|probe| probe.with_cwd()).transpose()?;
     }
-    if !env_keys.is_empty() {
-        let envs: BTreeMap<_,_> = std::env::vars_os()
-            // `as &OsStr` required per https://stackoverflow.com/q/65549983/113632
-            .filter(|(k,_)| env_keys.contains(k as &OsStr)).collect();
-        command = command.with_envs(&envs);
+    if key_on_binary {
+        command = command.with_executable_identity()?;
     }
+    if git_dirty {
+        command = command.with_dirty_git_state()?;
+    } else if git_state {
+        command = command.with_git_state()?;
+    }
+    if pty {
+        command = command.with_pty();
+    }
+    if let Some(expire_at) = expire_at {
+        command = command.with_expiry(&expire_at)?;
+    }
+    command = env_keys.apply(command);
 
     if warm && !force {
         force_update_async()?;
@@ -65,27 +546,40 @@ fn run(root_dir: Option<PathBuf>, discard_failures: bool, scope: Option<&str>,
 
     let (invocation, age) = if force {
         (bkt.refresh(&command, ttl)?, Duration::from_secs(0))
+    } else if let (Some(probe), true) = (&validate_with, revalidate) {
+        (bkt.refresh_if_changed(&command, probe, ttl)?.0, Duration::from_secs(0))
+    } else if let Some(probe) = &validate_with {
+        bkt.retrieve_validated(&command, probe, ttl)?
     } else {
         bkt.retrieve(&command, ttl)?
     };
 
+    if !watch_paths.is_empty() {
+        bkt.register_watch(&command, watch_paths, ttl, on_change)?;
+    }
+
     if let Some(stale) = stale {
         if age > stale {
-            force_update_async()?;
+            match validate_with {
+                Some(_) => update_async("--revalidate")?,
+                None => force_update_async()?,
+            }
         }
     }
 
-    io::stdout().write_all(invocation.stdout()).unwrap();
-    io::stderr().write_all(invocation.stderr()).unwrap();
+    if !invocation.streamed() {
+        invocation.replay(&mut output.color.wrap(io::stdout()), &mut output.color.wrap(io::stderr()),
+                          output.replay_timing).unwrap();
+    }
     Ok(invocation.exit_code())
 }
 
 fn main() {
-    let matches = App::new(crate_name!())
+    let app = App::new(crate_name!())
         .version(crate_version!())
         .about(crate_description!())
         .arg(Arg::with_name("command")
-            .required(true)
+            .required_unless_one(&["stats", "gc", "invalidate-tag", "export", "import", "warm-from", "watch"])
             .multiple(true)
             .last(true)
             .help("The command to run"))
@@ -94,15 +588,64 @@ fn main() {
             .visible_alias("ttl")
             .default_value("60s")
             .help("Duration the cached result will be valid"))
+        .arg(Arg::with_name("expire-at")
+            .long("expire-at")
+            .takes_value(true)
+            .value_name("SCHEDULE")
+            .help("Also expire the cached result at the next local time matching SCHEDULE, \
+                   regardless of when it was cached: midnight, hourly, weekly, monthly, yearly, a \
+                   time of day such as 06:30, or a cron expression such as '0 9 * * 1-5'. Unless \
+                   --ttl is also set, results are otherwise valid for the whole period"))
         .arg(Arg::with_name("stale")
             .long("stale")
             .takes_value(true)
             .conflicts_with("warm")
             .help("Duration after which the cached result will be asynchronously refreshed"))
+        .arg(Arg::with_name("validate-with")
+            .long("validate-with")
+            .takes_value(true)
+            .value_name("CMD")
+            .requires("stale")
+            .validator(|v| match shlex::split(&v) {
+                Some(words) if !words.is_empty() => Ok(()),
+                _ => Err(format!("'{}' is not a valid command", v)),
+            })
+            .help("A cheap command, such as 'git rev-parse HEAD', whose output changes whenever the \
+                   cached command's would. When --stale triggers a refresh CMD is run first, and \
+                   if its output is unchanged since the command was cached the cached result's \
+                   age is reset rather than re-running the command. CMD is split into words as \
+                   in a shell, but not run by one"))
+        .arg(Arg::with_name("revalidate")
+            .long("revalidate")
+            .takes_value(false)
+            .hidden(true)
+            .requires("validate-with")
+            .conflicts_with_all(&["warm", "force"]))
         .arg(Arg::with_name("warm")
             .long("warm")
             .takes_value(false)
             .help("Asynchronously execute and cache the given command, even if it's already cached"))
+        .arg(Arg::with_name("warm-from")
+            .long("warm-from")
+            .takes_value(true)
+            .value_name("FILE")
+            // Only the manifest's per-command options, and options configuring the cache itself, apply
+            .conflicts_with_all(&["command", "warm", "stale", "stats", "gc", "invalidate-tag", "export", "import",
+                                  "read-only", "benchmark", "expire-at", "cwd", "key-on-binary", "git-state",
+                                  "git-dirty", "env", "env-regex", "env-all-except", "env-secret", "env-clear",
+                                  "discard-failures", "retries", "retry-backoff", "retry-on", "max-rate",
+                                  "max-tag-rate", "max-output", "truncate-output", "stream", "interleave",
+                                  "replay-timing", "pty", "color", "tag", "watch-path", "watch"])
+            .help("Instead of running a command, asynchronously execute and cache every command \
+                   listed in FILE, one per line. Each line may start with --ttl, --scope, --tag, \
+                   --cwd=DIR, or --env=NAME options followed by --, which apply to that command. \
+                   With --force the commands are executed synchronously instead"))
+        .arg(Arg::with_name("jobs")
+            .long("jobs")
+            .takes_value(true)
+            .value_name("N")
+            .requires("warm-from")
+            .help("How many --warm-from commands to execute concurrently (default 4)"))
         .arg(Arg::with_name("force")
             .long("force")
             .takes_value(false)
@@ -114,37 +657,414 @@ fn main() {
             .takes_value(false)
             .help("Includes the current working directory in the cache key, so that the same \
                    command run in different directories caches separately"))
+        .arg(Arg::with_name("key-on-binary")
+            .long("key-on-binary")
+            .takes_value(false)
+            .help("Includes the location and modification time of the command's executable in \
+                   the cache key, so that upgrading or replacing the executable invalidates \
+                   previously cached results"))
+        .arg(Arg::with_name("git-state")
+            .long("git-state")
+            .takes_value(false)
+            .help("Includes the current commit and staged changes of the git repository containing \
+                   the working directory in the cache key, so that committing, checking out \
+                   another branch, or staging changes invalidates previously cached results"))
+        .arg(Arg::with_name("git-dirty")
+            .long("git-dirty")
+            .takes_value(false)
+            .help("Like --git-state, but also includes uncommitted edits to the working tree in \
+                   the cache key. This runs 'git status', which is slower"))
         .arg(Arg::with_name("env")
             .long("use-environment")
             .visible_alias("env")
             .takes_value(true)
             .multiple(true)
             .help("Includes the given environment variable in the cache key, so that the same \
-                   command run with different values for the given variables caches separately"))
+                   command run with different values for the given variables caches separately. \
+                   Glob patterns such as 'AWS_*' include all matching variables"))
+        .arg(Arg::with_name("env-regex")
+            .long("env-regex")
+            .takes_value(true)
+            .multiple(true)
+            .validator(|r| Regex::new(&r).map(|_| ()).map_err(|e| e.to_string()))
+            .help("Includes all environment variables whose names match the given regular \
+                   expression in the cache key"))
+        .arg(Arg::with_name("env-all-except")
+            .long("env-all-except")
+            .takes_value(true)
+            .multiple(true)
+            .help("Includes all environment variables in the cache key except those matching the \
+                   given names or glob patterns"))
+        .arg(Arg::with_name("env-secret")
+            .long("env-secret")
+            .takes_value(true)
+            .multiple(true)
+            .help("Like --env, but the variable's value is treated as a secret; only a salted hash \
+                   of the value is included in the cache key and written to the cache directory"))
+        .arg(Arg::with_name("env-clear")
+            .long("env-clear")
+            .takes_value(false)
+            .help("Runs the command with only the environment variables included in the cache key \
+                   (plus PATH and HOME, which are then also included in the key), so that the \
+                   cached result cannot depend on any other variables"))
         .arg(Arg::with_name("discard-failures")
             .long("discard-failures")
             .help("Don't cache invocations that fail (non-zero exit code). USE CAUTION when \
                       passing this flag, as unexpected failures can lead to a spike in invocations \
                       which can exacerbate ongoing issues, effectively a DDoS."))
+        .arg(Arg::with_name("retries")
+            .long("retries")
+            .takes_value(true)
+            .value_name("N")
+            .help("Re-run the command up to N times if it fails (non-zero exit code), and only \
+                   cache the result of the last attempt"))
+        .arg(Arg::with_name("retry-backoff")
+            .long("retry-backoff")
+            .takes_value(true)
+            .value_name("DURATION")
+            .requires("retries")
+            .help("How long to wait before the first retry, doubling after each subsequent \
+                   attempt (default 1s)"))
+        .arg(Arg::with_name("retry-on")
+            .long("retry-on")
+            .takes_value(true)
+            .value_name("CODE")
+            .multiple(true)
+            .requires("retries")
+            .help("Only retry the command if it exits with this code, rather than any non-zero \
+                   code. Can be passed multiple times"))
+        .arg(Arg::with_name("max-rate")
+            .long("max-rate")
+            .takes_value(true)
+            .value_name("N/DURATION")
+            .help("Execute the command at most N times per DURATION (e.g. 1/10s), across all \
+                   processes using the cache directory. When the limit is reached the last \
+                   cached result is used even if it has expired, or if there is none bkt fails \
+                   without running the command"))
+        .arg(Arg::with_name("max-tag-rate")
+            .long("max-tag-rate")
+            .takes_value(true)
+            .value_name("N/DURATION")
+            .requires("tag")
+            .help("Like --max-rate, but limits executions of all commands sharing any of the \
+                   given --tag values"))
+        .arg(Arg::with_name("max-output")
+            .long("max-output")
+            .takes_value(true)
+            .value_name("SIZE")
+            .validator(|s| parse_size(&s).map(|_| ()))
+            .help("Capture at most SIZE bytes (e.g. 512K or 10M) of the command's combined stdout \
+                   and stderr. Invocations that produce more output are not cached, unless \
+                   --truncate-output is also passed"))
+        .arg(Arg::with_name("truncate-output")
+            .long("truncate-output")
+            .takes_value(false)
+            .requires("max-output")
+            .help("Cache invocations that exceed --max-output anyway, keeping only the first SIZE \
+                   bytes of output followed by a '[bkt: output truncated ...]' marker"))
+        .arg(Arg::with_name("stream")
+            .long("stream")
+            .takes_value(false)
+            .help("When the command is executed, write its output as it is produced rather than \
+                   after it exits. Output beyond --max-output is still written in full"))
+        .arg(Arg::with_name("interleave")
+            .long("interleave")
+            .takes_value(false)
+            .help("Record the order in which the command writes to stdout and stderr, so cached \
+                   results replay them interleaved as originally printed rather than all of \
+                   stdout followed by all of stderr"))
+        .arg(Arg::with_name("replay-timing")
+            .long("replay-timing")
+            .takes_value(false)
+            .requires("interleave")
+            .help("When replaying an --interleave'd result, also reproduce the delays between \
+                   each piece of output"))
+        .arg(Arg::with_name("pty")
+            .long("pty")
+            .takes_value(false)
+            .help("Run the command with its stdout attached to a pseudo-terminal, so that programs \
+                   which only print colors when writing to a terminal still do so. Cached \
+                   separately from the same command run without --pty"))
+        .arg(Arg::with_name("color")
+            .long("color")
+            .takes_value(true)
+            .value_name("WHEN")
+            .possible_values(&["always", "auto", "never"])
+            // Streamed output is written as it's produced, without passing through the filter
+            .conflicts_with("stream")
+            .help("Whether to write ANSI escape sequences, such as colors, in the command's output \
+                   (default 'always'). 'auto' removes them when bkt's stdout or stderr isn't a terminal. Cannot be \
+                   combined with --stream"))
         .arg(Arg::with_name("scope")
             .long("scope")
             .takes_value(true)
             .help("If set, all cached data will be scoped to this value, preventing collisions \
                    with commands cached with different scopes"))
+        .arg(Arg::with_name("tag")
+            .long("tag")
+            .takes_value(true)
+            .multiple(true)
+            .validator(validate_tag)
+            .help("Tags the cached result with the given name, so that it can later be expired \
+                   along with all other results sharing that tag via --invalidate-tag"))
+        .arg(Arg::with_name("watch-path")
+            .long("watch-path")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("PATH")
+            .conflicts_with_all(&["read-only", "benchmark"])
+            .help("Registers that the cached result depends on PATH, so that a --watch process \
+                   invalidates it as soon as PATH (or, for a directory, its immediate contents) \
+                   changes"))
+        .arg(Arg::with_name("watch-refresh")
+            .long("watch-refresh")
+            .takes_value(false)
+            .requires("watch-path")
+            .conflicts_with("env-secret")
+            .help("When a --watch-path changes, execute and cache the command again rather than \
+                   just invalidating its cached result"))
+        .arg(Arg::with_name("watch")
+            .long("watch")
+            .takes_value(false)
+            .conflicts_with_all(&["command", "warm", "force", "stale", "stats", "gc", "invalidate-tag",
+                                  "export", "import", "warm-from", "read-only", "benchmark"])
+            .help("Instead of running a command, run until killed, invalidating or refreshing \
+                   cached results (only those in the given --scope, if set) as soon as the paths \
+                   registered with --watch-path change. Only supported on Linux"))
+        .arg(Arg::with_name("invalidate-tag")
+            .long("invalidate-tag")
+            .takes_value(true)
+            .multiple(true)
+            .validator(validate_tag)
+            .conflicts_with_all(&["command", "stats", "benchmark"])
+            .help("Instead of running a command, expire all cached results (in any scope) that \
+                   were tagged with the given name"))
+        .arg(Arg::with_name("export")
+            .long("export")
+            .takes_value(true)
+            .value_name("FILE")
+            .conflicts_with_all(&["command", "stats", "invalidate-tag", "import", "benchmark"])
+            .help("Instead of running a command, write all unexpired cached results (only those \
+                   in the given --scope, if set) to FILE, which can be loaded into another cache \
+                   with --import"))
+        .arg(Arg::with_name("import")
+            .long("import")
+            .takes_value(true)
+            .value_name("FILE")
+            .conflicts_with_all(&["command", "stats", "invalidate-tag", "benchmark"])
+            .help("Instead of running a command, load cached results written by --export from \
+                   FILE (only those in the given --scope, if set)"))
+        .arg(Arg::with_name("rebase-timestamps")
+            .long("rebase-timestamps")
+            .takes_value(false)
+            .requires("import")
+            .help("When importing, treat results as if they were cached relative to when the \
+                   file was exported, rather than their original time, so they don't expire \
+                   sooner due to the time elapsed since the export"))
         .arg(Arg::with_name("cache_dir")
             .long("cache-dir")
             .takes_value(true)
             .help("The directory under which to persist cached invocations; defaults to the \
                    system's temp directory. Setting this to a directory backed by RAM or an SSD, \
                    such as a tmpfs partition, will significantly reduce caching overhead."))
-        .get_matches();
-    let root_dir = matches.value_of("cache_dir").map(PathBuf::from);
+        .arg(Arg::with_name("max-ttl")
+            .long("max-ttl")
+            .takes_value(true)
+            .help("Never use results from the --cache-dir that are older than this, whatever \
+                   --ttl is requested. Useful with --shared-cache-dir to keep the local cache \
+                   fresher than the shared one"))
+        .arg(Arg::with_name("shared-cache-dir")
+            .long("shared-cache-dir")
+            .takes_value(true)
+            .help("An additional, slower directory to cache invocations in, such as one shared \
+                   by a team on a network filesystem. It is checked when --cache-dir misses, \
+                   results found there are copied into --cache-dir, and new results are written \
+                   to both"))
+        .arg(Arg::with_name("shared-max-ttl")
+            .long("shared-max-ttl")
+            .takes_value(true)
+            .requires("shared-cache-dir")
+            .help("Like --max-ttl, but for the --shared-cache-dir"))
+        .arg(Arg::with_name("read-only")
+            .long("read-only")
+            .takes_value(false)
+            .conflicts_with_all(&["warm", "stale", "invalidate-tag", "import", "max-rate", "max-tag-rate"])
+            .help("Never write to the cache directory, e.g. because it is mounted read-only. \
+                   Cached results are still used, but on a cache miss the command's result is \
+                   not stored, and expired data is not cleaned up"))
+        .arg(Arg::with_name("verbose")
+            .long("verbose")
+            .short("v")
+            .multiple(true)
+            .takes_value(false)
+            .help("Log cache decisions (lookups, stores, cleanups, background refreshes) to \
+                   stderr; pass twice for more detail. The BKT_LOG environment variable can also \
+                   be set to a log level such as 'debug'"))
+        .arg(Arg::with_name("benchmark")
+            .long("benchmark")
+            .takes_value(true)
+            .min_values(0)
+            .require_equals(true)
+            .value_name("ITERATIONS")
+            .validator(|i| match i.parse::<u32>() {
+                Ok(i) if i > 0 => Ok(()),
+                _ => Err(format!("'{}' is not a positive number of iterations", i)),
+            })
+            .conflicts_with_all(&["scope", "warm", "force", "stale"])
+            .help("Instead of printing the command's output, measure how long the command takes \
+                   to run directly, on a cache miss, and on a cache hit (with the other flags \
+                   given) over several iterations (default 5), and report whether caching it is \
+                   worthwhile"))
+        .arg(Arg::with_name("stats")
+            .long("stats")
+            .takes_value(false)
+            .conflicts_with_all(&["command", "warm", "force"])
+            .help("Instead of running a command, report how often cached commands hit or miss the \
+                   cache and how much runtime caching has saved, sorted by savings"))
+        .arg(Arg::with_name("gc")
+            .long("gc")
+            .takes_value(false)
+            .conflicts_with_all(&["command", "stats", "read-only"])
+            .help("Instead of running a command, remove all stale data from the cache now and \
+                   report how many files and bytes were reclaimed"))
+        .arg(Arg::with_name("cleanup-throttle")
+            .long("cleanup-throttle")
+            .takes_value(true)
+            .value_name("DURATION")
+            .help("The minimum time between automatic cleanups of stale data in the cache \
+                   directory, which run in the background on cache misses (default 30s)"))
+        .arg(Arg::with_name("stale-lock-timeout")
+            .long("stale-lock-timeout")
+            .takes_value(true)
+            .value_name("DURATION")
+            .help("How old a cleanup lock held by a process that may still be running must be \
+                   before it is reported as stale, on filesystems that don't support flock() \
+                   (default 10m)"));
+    #[cfg(feature = "http")]
+    let app = app.arg(Arg::with_name("remote-cache")
+        .long("remote-cache")
+        .takes_value(true)
+        .help("Also share cached invocations via the HTTP server at this URL, which must support \
+               GET and PUT requests, such as a Bazel remote cache or a WebDAV directory. If the \
+               server is unavailable only the local cache is used"))
+        .arg(Arg::with_name("remote-timeout")
+            .long("remote-timeout")
+            .takes_value(true)
+            .value_name("DURATION")
+            .requires("remote-cache")
+            .help("How long to wait for each request to the --remote-cache before falling back to \
+                   the local cache (default 10s)"));
+    let matches = app.get_matches();
+    init_logging(matches.occurrences_of("verbose"));
+    let config = CacheConfig {
+        root_dir: matches.value_of("cache_dir").map(PathBuf::from),
+        max_ttl: matches.value_of("max-ttl").map(|_|
+            value_t_or_exit!(matches.value_of("max-ttl"), humantime::Duration).into()),
+        shared_dir: matches.value_of("shared-cache-dir").map(PathBuf::from),
+        shared_max_ttl: matches.value_of("shared-max-ttl").map(|_|
+            value_t_or_exit!(matches.value_of("shared-max-ttl"), humantime::Duration).into()),
+        #[cfg(feature = "http")]
+        remote_url: matches.value_of("remote-cache"),
+        #[cfg(feature = "http")]
+        remote_timeout: matches.value_of("remote-timeout").map(|_|
+            value_t_or_exit!(matches.value_of("remote-timeout"), humantime::Duration).into())
+            .unwrap_or(Duration::from_secs(10)),
+        scope: matches.value_of("scope"),
+        read_only: matches.is_present("read-only"),
+        cleanup_policy: {
+            let mut policy = CleanupPolicy::default();
+            if matches.is_present("cleanup-throttle") {
+                policy = policy.throttle(
+                    value_t_or_exit!(matches.value_of("cleanup-throttle"), humantime::Duration).into());
+            }
+            if matches.is_present("stale-lock-timeout") {
+                policy = policy.stale_lock_after(
+                    value_t_or_exit!(matches.value_of("stale-lock-timeout"), humantime::Duration).into());
+            }
+            policy
+        },
+    };
     let discard_failures = matches.is_present("discard-failures");
-    let scope = matches.value_of("scope");
+    let mut retry_policy = RetryPolicy::default();
+    if matches.is_present("retries") {
+        retry_policy = retry_policy.retries(value_t_or_exit!(matches.value_of("retries"), u32));
+    }
+    if matches.is_present("retry-backoff") {
+        retry_policy = retry_policy.backoff(
+            value_t_or_exit!(matches.value_of("retry-backoff"), humantime::Duration).into());
+    }
+    if matches.is_present("retry-on") {
+        retry_policy = retry_policy.retry_on(values_t_or_exit!(matches.values_of("retry-on"), i32));
+    }
+
+    if matches.is_present("stats") {
+        exit_with(print_stats(&config));
+    }
+    if matches.is_present("gc") {
+        exit_with(gc(&config));
+    }
+    if let Some(tags) = matches.values_of("invalidate-tag") {
+        exit_with(invalidate_tags(&config, tags.collect()));
+    }
+    if let Some(file) = matches.value_of_os("export") {
+        exit_with(export(&config, file));
+    }
+    if let Some(file) = matches.value_of_os("import") {
+        exit_with(import(&config, file, matches.is_present("rebase-timestamps")));
+    }
+    if matches.is_present("watch") {
+        exit_with(watch(&config));
+    }
+    if let Some(file) = matches.value_of_os("warm-from") {
+        let ttl = value_t_or_exit!(matches.value_of("ttl"), humantime::Duration).into();
+        let jobs = match matches.value_of("jobs") {
+            Some(_) => value_t_or_exit!(matches.value_of("jobs"), usize),
+            None => 4,
+        };
+        exit_with(warm_from(&config, file, ttl, jobs, matches.is_present("force")));
+    }
+    let tags = matches.values_of("tag").map(|t| t.collect()).unwrap_or_default();
+    let watch_paths = matches.values_of_os("watch-path").map(|p| p.collect()).unwrap_or_default();
+    let on_change = if matches.is_present("watch-refresh") { OnChange::Refresh } else { OnChange::Invalidate };
+    let max_rate = matches.value_of("max-rate").map(|_| value_t_or_exit!(matches.value_of("max-rate"), RateLimit));
+    let max_tag_rate = matches.value_of("max-tag-rate")
+        .map(|_| value_t_or_exit!(matches.value_of("max-tag-rate"), RateLimit));
+    let output = OutputOptions {
+        limit: matches.value_of("max-output")
+            .map(|s| OutputLimit::new(parse_size(s).expect("Validated"))
+                .truncate(matches.is_present("truncate-output"))),
+        stream: matches.is_present("stream"),
+        interleave: matches.is_present("interleave"),
+        replay_timing: matches.is_present("replay-timing"),
+        color: match matches.value_of("color") {
+            Some("auto") => Color::Auto,
+            Some("never") => Color::Never,
+            _ => Color::Always,
+        },
+    };
+
     let command = CommandDesc::new(matches.values_of_os("command").expect("Required").collect::<Vec<_>>());
     let use_cwd = matches.is_present("cwd");
-    let env = matches.values_of_os("env").map(|e| e.collect()).unwrap_or_else(BTreeSet::new);
-    let ttl = value_t_or_exit!(matches.value_of("ttl"), humantime::Duration).into();
+    let key_on_binary = matches.is_present("key-on-binary");
+    let git_state = matches.is_present("git-state");
+    let git_dirty = matches.is_present("git-dirty");
+    let pty = matches.is_present("pty");
+    let env = EnvKeys {
+        patterns: matches.values_of_os("env").map(|e| e.collect()).unwrap_or_default(),
+        regexes: matches.values_of("env-regex")
+            .map(|e| e.map(|r| Regex::new(r).expect("Validated")).collect()).unwrap_or_default(),
+        all_except: matches.values_of("env-all-except").map(|e| e.collect()),
+        secrets: matches.values_of_os("env-secret").map(|e| e.collect()).unwrap_or_default(),
+        isolated: matches.is_present("env-clear"),
+    };
+    let expire_at = matches.value_of("expire-at")
+        .map(|_| value_t_or_exit!(matches.value_of("expire-at"), Schedule));
+    let ttl = match &expire_at {
+        Some(expire_at) if matches.occurrences_of("ttl") == 0 =>
+            schedule_period(expire_at).unwrap_or_else(|e| exit_with(Err(e))),
+        _ => value_t_or_exit!(matches.value_of("ttl"), humantime::Duration).into(),
+    };
 
     // https://github.com/clap-rs/clap/discussions/2453
     let stale = matches.value_of("stale")
@@ -154,11 +1074,26 @@ fn main() {
                     format!("The argument '{}' isn't a valid value", v)))
                 .unwrap_or_else(|e| e.exit())
                 .into());
+    let validate_with = matches.value_of("validate-with")
+        .map(|probe| CommandDesc::new(shlex::split(probe).expect("Validated")));
+    let revalidate = matches.is_present("revalidate");
     let warm = matches.is_present("warm");
 
     let force = matches.is_present("force");
 
-    match run(root_dir, discard_failures, scope, command, use_cwd, env, ttl, stale, warm, force) {
+    if matches.is_present("benchmark") {
+        let iterations = match matches.value_of("benchmark") {
+            Some(_) => value_t_or_exit!(matches.value_of("benchmark"), u32),
+            None => 5,
+        };
+        exit_with(benchmark(&config, command, iterations));
+    }
+
+    exit_with(run(&config, discard_failures, retry_policy, max_rate, max_tag_rate, output, tags, watch_paths, on_change, command, use_cwd, key_on_binary, git_state, git_dirty, pty, env, expire_at, ttl, stale, validate_with, revalidate, warm, force));
+}
+
+fn exit_with(result: Result<i32>) -> ! {
+    match result {
         Ok(code) => exit(code),
         Err(msg) => {
             eprintln!("bkt: {:#}", msg);
@@ -165,6 +165,27 @@ mod cli {
         assert_eq!(succeed(bkt(dir.path("cache")).args(args1)), "2");
     }
 
+    #[test]
+    fn expire_at() {
+        let dir = TestDir::temp();
+        let file = dir.path("file");
+        let args = ["--expire-at=midnight", "--", "bash", "-c", COUNT_INVOCATIONS, "arg0", file.to_str().unwrap()];
+        assert_eq!(succeed(bkt(dir.path("cache")).args(args)), "1");
+        // without --ttl results are valid until the schedule's next boundary
+        make_dir_stale(dir.path("cache"), Duration::from_secs(120)).unwrap();
+        assert_eq!(succeed(bkt(dir.path("cache")).args(args)), "1");
+        let with_ttl = join(&["--ttl=1m"], &args);
+        assert_eq!(succeed(bkt(dir.path("cache")).args(&with_ttl)), "2");
+
+        // results cached under different schedules have different expirations
+        let other = join(&["--expire-at=0 12 * * *"], &args[1..]);
+        assert_eq!(succeed(bkt(dir.path("cache")).args(&other)), "3");
+        assert_eq!(succeed(bkt(dir.path("cache")).args(&other)), "3");
+
+        let invalid = run(bkt(dir.path("cache")).arg("--expire-at=noon").args(&args[1..]));
+        assert_eq!(invalid.status, Some(1));
+    }
+
     #[test]
     fn cache_refreshes_in_background() {
         let dir = TestDir::temp();
@@ -223,8 +244,9 @@ mod cli {
         assert_eq!(run(bkt(dir.path("cache")).args(&discard_stale_args)),
                    CmdResult { out: "1".into(), err: "".into(), status: Some(1) });
 
+        // Wait for both background refreshes, not just the first, to complete
         for _ in 1..10 {
-            if modtime(&file) > last_mod { break; }
+            if modtime(&file) > last_mod && std::fs::read_to_string(&file).unwrap() == "..." { break; }
             std::thread::sleep(Duration::from_millis(100));
         }
         // Command ran
@@ -236,6 +258,109 @@ mod cli {
                    CmdResult { out: "1".into(), err: "".into(), status: Some(1) });
     }
 
+    #[test]
+    fn retries() {
+        let dir = TestDir::temp();
+        let file = dir.path("file");
+        // Fails until the third attempt
+        let cmd = ["--", "bash", "-c", r#"echo >> "${1:?}"; n=$(wc -l < "${1:?}"); echo "$n"; (( n >= 3 ))"#,
+                   "arg0", file.to_str().unwrap()];
+
+        let output = run(bkt(dir.path("cache")).args(join(&["--retries=1", "--retry-backoff=10ms"], &cmd)));
+        assert_eq!(output, CmdResult { out: "2\n".into(), err: "".into(), status: Some(1) });
+
+        std::fs::remove_file(&file).unwrap();
+        let retry_args = ["--retries=5", "--retry-backoff=10ms", "--retry-on=1", "--scope=retry"];
+        assert_eq!(succeed(bkt(dir.path("cache")).args(join(&retry_args, &cmd))), "3\n");
+        // the success was cached
+        assert_eq!(succeed(bkt(dir.path("cache")).args(join(&retry_args, &cmd))), "3\n");
+    }
+
+    #[test]
+    fn max_output() {
+        let dir = TestDir::temp();
+        let file = dir.path("file");
+        let cmd = ["--", "bash", "-c", r#"echo >> "${1:?}"; seq 1000"#, "arg0", file.to_str().unwrap()];
+        let full: String = (1..=1000).map(|n| format!("{}\n", n)).collect();
+        let runs = || std::fs::read_to_string(&file).unwrap().len();
+
+        // the full output is streamed, but not cached
+        assert_eq!(succeed(bkt(dir.path("cache")).args(join(&["--max-output=1K", "--stream"], &cmd))), full);
+        assert_eq!(succeed(bkt(dir.path("cache")).args(join(&["--max-output=1K", "--stream"], &cmd))), full);
+        assert_eq!(runs(), 2);
+
+        // without streaming only the captured output is available
+        let output = succeed(bkt(dir.path("cache")).args(join(&["--max-output=16", "--truncate-output"], &cmd)));
+        assert_eq!(output, "1\n2\n3\n4\n5\n6\n7\n8\n[bkt: output truncated after 16 bytes]\n");
+        assert_eq!(succeed(bkt(dir.path("cache")).args(join(&["--max-output=16", "--truncate-output"], &cmd))), output);
+        assert_eq!(runs(), 3);
+
+        assert_eq!(succeed(bkt(dir.path("cache")).args(join(&["--max-output=1M", "--scope=big"], &cmd))), full);
+
+        let output = run(bkt(dir.path("cache")).args(join(&["--max-output=10X"], &cmd)));
+        assert_eq!(output.status, Some(1));
+        assert!(output.err.contains("Unknown size suffix"), "{}", output.err);
+    }
+
+    #[test]
+    fn interleave() {
+        let dir = TestDir::temp();
+        let cmd = ["--", "bash", "-c", "echo out1; sleep .1; echo err >&2; sleep .1; echo out2"];
+        // Writes both stdout and stderr to the same file, to observe their ordering
+        let combined = |args: &[&str]| {
+            let file = dir.path("output");
+            let out = File::create(&file).unwrap();
+            let status = bkt(dir.path("cache")).args(join(args, &cmd))
+                .stdout(out.try_clone().unwrap()).stderr(out).status().unwrap();
+            assert!(status.success());
+            std::fs::read_to_string(&file).unwrap()
+        };
+
+        assert_eq!(combined(&[]), "out1\nout2\nerr\n");
+        assert_eq!(combined(&["--interleave", "--scope=interleave"]), "out1\nerr\nout2\n");
+        assert_eq!(combined(&["--interleave", "--scope=interleave"]), "out1\nerr\nout2\n");
+        assert_eq!(combined(&["--interleave", "--replay-timing", "--scope=interleave"]), "out1\nerr\nout2\n");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn pty() {
+        let dir = TestDir::temp();
+        let cmd = ["--", "bash", "-c", r#"if [[ -t 1 ]]; then printf '\e[31mred\e[0m\n'; else echo plain; fi"#];
+
+        assert_eq!(succeed(bkt(dir.path("cache")).args(cmd)), "plain\n");
+        assert_eq!(succeed(bkt(dir.path("cache")).args(join(&["--pty"], &cmd))), "\x1b[31mred\x1b[0m\n");
+        // stdout is a pipe, so the cached escapes are removed
+        assert_eq!(succeed(bkt(dir.path("cache")).args(join(&["--pty", "--color=auto"], &cmd))), "red\n");
+        assert_eq!(succeed(bkt(dir.path("cache")).args(join(&["--pty", "--color=never"], &cmd))), "red\n");
+        // streamed output isn't filtered, so the combination is rejected
+        let output = run(bkt(dir.path("cache")).args(join(&["--pty", "--color=never", "--stream"], &cmd)));
+        assert_eq!(output.status, Some(1));
+    }
+
+    #[test]
+    fn max_rate() {
+        let dir = TestDir::temp();
+        let file = dir.path("file");
+        let args = ["--max-rate=1/1h", "--", "bash", "-c", COUNT_INVOCATIONS, "arg0", file.to_str().unwrap()];
+
+        assert_eq!(succeed(bkt(dir.path("cache")).args(args)), "1");
+        // the expired result is used rather than running the command again
+        make_dir_stale(dir.path("cache"), Duration::from_secs(120)).unwrap();
+        assert_eq!(succeed(bkt(dir.path("cache")).args(args)), "1");
+        assert_eq!(succeed(bkt(dir.path("cache")).arg("--force").args(args)), "1");
+
+        let output = run(bkt(dir.path("cache")).args(["--max-rate=1/1h", "--scope=new"]).args(&args[1..]));
+        assert_eq!(output.out, "2");
+        let output = run(bkt(dir.path("cache")).args(["--max-rate=1/1h", "--scope=newer", "--tag=t", "--max-tag-rate=1/1h"])
+            .args(&args[1..]));
+        assert_eq!(output.out, "3");
+        let output = run(bkt(dir.path("cache")).args(["--scope=newest", "--tag=t", "--max-tag-rate=1/1h"])
+            .args(&args[1..]));
+        assert_eq!(output.status, Some(127));
+        assert!(output.err.contains("Rate limited"), "{}", output.err);
+    }
+
     #[test]
     fn respects_cache_dir() {
         let dir = TestDir::temp();
@@ -303,6 +428,32 @@ mod cli {
         assert!(cwd_dir2.trim().ends_with("/dir2"));
     }
 
+    #[test]
+    #[cfg(unix)]
+    fn respects_key_on_binary() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = TestDir::temp().create("bin", FileType::Dir);
+        let tool = dir.path("bin/tool");
+        let install = |version: &str| {
+            std::fs::write(&tool, format!("#!/bin/sh\necho {}\n", version)).unwrap();
+            std::fs::set_permissions(&tool, std::fs::Permissions::from_mode(0o755)).unwrap();
+        };
+        let path = format!("{}:{}", dir.path("bin").display(), std::env::var("PATH").unwrap());
+        let args = ["--", "tool"];
+        let binary_args = join(&["--key-on-binary"], &args);
+
+        install("v1");
+        assert_eq!(succeed(bkt(dir.path("cache")).args(args).env("PATH", &path)), "v1\n");
+        assert_eq!(succeed(bkt(dir.path("cache")).args(&binary_args).env("PATH", &path)), "v1\n");
+
+        // replace the executable, ensuring its mtime changes
+        std::fs::remove_file(&tool).unwrap();
+        install("v2");
+        filetime::set_file_mtime(&tool, filetime::FileTime::from_unix_time(1000, 0)).unwrap();
+        assert_eq!(succeed(bkt(dir.path("cache")).args(args).env("PATH", &path)), "v1\n"); // stale
+        assert_eq!(succeed(bkt(dir.path("cache")).args(&binary_args).env("PATH", &path)), "v2\n");
+    }
+
     #[test]
     #[cfg(not(feature = "debug"))] // See lib's bkt_tests::with_env
     fn respects_env() {
@@ -330,6 +481,53 @@ mod cli {
         assert_eq!(env, "foo:2 bar:2 baz:2"); // BAZ doesn't invalidate cache
     }
 
+    #[test]
+    #[cfg(not(feature = "debug"))] // See lib's bkt_tests::with_env
+    fn respects_env_patterns() {
+        let dir = TestDir::temp();
+        let args = ["--", "bash", "-c", r#"printf 'foo:%s bar:%s baz:%s' "$APP_FOO" "$APP_BAR" "$BAZ""#];
+        let call = |flag: &str, foo: &str, bar: &str, baz: &str| succeed(bkt(dir.path("cache"))
+            .arg(flag).args(args).env("APP_FOO", foo).env("APP_BAR", bar).env("BAZ", baz));
+
+        assert_eq!(call("--env=APP_*", "1", "1", "1"), "foo:1 bar:1 baz:1");
+        assert_eq!(call("--env=APP_*", "1", "2", "2"), "foo:1 bar:2 baz:2");
+        assert_eq!(call("--env=APP_*", "1", "2", "3"), "foo:1 bar:2 baz:2"); // BAZ doesn't invalidate cache
+
+        assert_eq!(call("--env-regex=^APP_F", "1", "1", "1"), "foo:1 bar:1 baz:1");
+        assert_eq!(call("--env-regex=^APP_F", "1", "2", "2"), "foo:1 bar:1 baz:1");
+        assert_eq!(call("--env-regex=^APP_F", "2", "2", "2"), "foo:2 bar:2 baz:2");
+
+        assert_eq!(call("--env-all-except=APP_*", "1", "1", "1"), "foo:1 bar:1 baz:1");
+        assert_eq!(call("--env-all-except=APP_*", "2", "2", "1"), "foo:1 bar:1 baz:1");
+        assert_eq!(call("--env-all-except=APP_*", "2", "2", "2"), "foo:2 bar:2 baz:2");
+
+        let invalid = run(bkt(dir.path("cache")).arg("--env-regex=(").args(args));
+        assert_ne!(invalid.status, Some(0));
+    }
+
+    #[test]
+    #[cfg(not(feature = "debug"))] // See lib's bkt_tests::with_env
+    fn respects_env_clear() {
+        let dir = TestDir::temp();
+        let args = ["--env-clear", "--env=FOO", "--", "bash", "-c", r#"printf 'foo:%s bar:%s' "$FOO" "$BAR""#];
+
+        assert_eq!(succeed(bkt(dir.path("cache")).args(args).env("FOO", "1").env("BAR", "1")), "foo:1 bar:");
+        assert_eq!(succeed(bkt(dir.path("cache")).args(args).env("FOO", "2").env("BAR", "2")), "foo:2 bar:");
+    }
+
+    #[test]
+    #[cfg(not(feature = "debug"))] // See lib's bkt_tests::with_env
+    fn respects_env_secret() {
+        let dir = TestDir::temp();
+        let args = ["--env-secret=TOKEN", "--", "bash", "-c", r#"printf 'token:%s rand:%s' "$TOKEN" "$RANDOM""#];
+
+        let first = succeed(bkt(dir.path("cache")).args(args).env("TOKEN", "hunter2"));
+        assert!(first.starts_with("token:hunter2 "));
+        assert_eq!(succeed(bkt(dir.path("cache")).args(args).env("TOKEN", "hunter2")), first);
+        let changed = succeed(bkt(dir.path("cache")).args(args).env("TOKEN", "letmein"));
+        assert!(changed.starts_with("token:letmein "));
+    }
+
     #[test]
     fn no_debug_output() {
         let dir = TestDir::temp();
@@ -343,6 +541,19 @@ mod cli {
                    CmdResult { out: "".into(), err: "".into(), status: Some(0) });
     }
 
+    #[test]
+    fn verbose_output() {
+        let dir = TestDir::temp();
+        let args = ["--", "bash", "-c", "true"];
+
+        let miss = run(bkt(dir.path("cache")).arg("--verbose").args(args));
+        assert!(miss.err.contains("not found"), "{}", miss.err);
+        let hit = run(bkt(dir.path("cache")).args(args).env("BKT_LOG", "debug"));
+        assert!(hit.err.contains("found"), "{}", hit.err);
+        assert!(!hit.err.contains("not found"), "{}", hit.err);
+        assert_eq!(hit.out, miss.out);
+    }
+
     #[test]
     fn output_preserved() {
         let dir = TestDir::temp();
@@ -411,6 +622,131 @@ mod cli {
         assert_eq!(output, "awaiting\n");
     }
 
+    #[test]
+    fn warm_from() {
+        let dir = TestDir::temp();
+        let (file1, file2, file3) = (dir.path("file1"), dir.path("file2"), dir.path("file3"));
+        let script = r#"printf . >> "${1:?}"; cat "${1:?}""#;
+        let manifest = dir.path("manifest");
+        std::fs::write(&manifest, format!("# Commands to warm\n\
+            bash -c '{0}' arg0 {1}\n\
+            \n\
+            --ttl=1h --scope=manifest -- bash -c '{0}' arg0 {2}\n",
+            script, file1.display(), file2.display())).unwrap();
+        let warm_arg = format!("--warm-from={}", manifest.display());
+
+        assert_eq!(succeed(bkt(dir.path("cache")).args([&warm_arg, "--force"])), "");
+        assert_eq!(succeed(bkt(dir.path("cache")).args(["--", "bash", "-c", script, "arg0", file1.to_str().unwrap()])), ".");
+        assert_eq!(succeed(bkt(dir.path("cache")).args(["--ttl=1h", "--scope=manifest", "--", "bash", "-c", script,
+            "arg0", file2.to_str().unwrap()])), ".");
+
+        // --scope applies to lines that don't set their own
+        std::fs::remove_file(&file1).unwrap();
+        std::fs::remove_file(&file2).unwrap();
+        assert_eq!(succeed(bkt(dir.path("cache")).args([&warm_arg, "--scope=global", "--force"])), "");
+        assert_eq!(succeed(bkt(dir.path("cache")).args(["--scope=global", "--", "bash", "-c", script, "arg0",
+            file1.to_str().unwrap()])), ".");
+        assert_eq!(succeed(bkt(dir.path("cache")).args(["--ttl=1h", "--scope=manifest", "--", "bash", "-c", script,
+            "arg0", file2.to_str().unwrap()])), ".");
+
+        // without --force the commands are warmed in the background
+        std::fs::write(&manifest, format!("bash -c '{}' arg0 {}\n", script, file3.display())).unwrap();
+        assert_eq!(succeed(bkt(dir.path("cache")).arg(&warm_arg)), "");
+        for _ in 0..10 {
+            if file3.exists() { break; }
+            std::thread::sleep(Duration::from_millis(200));
+        }
+        assert!(file3.exists());
+
+        std::fs::write(&manifest, "date\n--bogus=1 -- date\n").unwrap();
+        let output = run(bkt(dir.path("cache")).arg(&warm_arg));
+        assert_eq!(output.status, Some(127));
+        assert!(output.err.contains("line 2") && output.err.contains("Unsupported option '--bogus'"), "{}", output.err);
+        // options without -- aren't run as a command
+        std::fs::write(&manifest, "--ttl=1h\n").unwrap();
+        let output = run(bkt(dir.path("cache")).arg(&warm_arg));
+        assert_eq!(output.status, Some(127));
+        assert!(output.err.contains("line 1") && output.err.contains("must be followed by --"), "{}", output.err);
+
+        // flags that only apply to a single command aren't silently ignored
+        let output = run(bkt(dir.path("cache")).args([&warm_arg, "--tag=foo"]));
+        assert_eq!(output.status, Some(1));
+    }
+
+    #[test]
+    fn validate_with() {
+        let dir = TestDir::temp();
+        let (file, version) = (dir.path("file"), dir.path("version"));
+        let probe = format!("--validate-with=cat {}", version.display());
+        let args = ["--ttl=1m", "--stale=10s", &probe, "--", "bash", "-c", COUNT_INVOCATIONS, "arg0", file.to_str().unwrap()];
+        std::fs::write(&version, "1").unwrap();
+
+        assert_eq!(succeed(bkt(dir.path("cache")).args(args)), "1");
+        make_dir_stale(dir.path("cache"), Duration::from_secs(20)).unwrap();
+        // the probe's output is unchanged, so the command isn't re-run
+        assert_eq!(succeed(bkt(dir.path("cache")).arg("--revalidate").args(args)), "1");
+        std::fs::write(&version, "2").unwrap();
+        assert_eq!(succeed(bkt(dir.path("cache")).arg("--revalidate").args(args)), "2");
+
+        // normally revalidation happens in the background
+        make_dir_stale(dir.path("cache"), Duration::from_secs(20)).unwrap();
+        std::fs::write(&version, "3").unwrap();
+        assert_eq!(succeed(bkt(dir.path("cache")).args(args)), "2");
+        for _ in 0..10 {
+            if std::fs::read_to_string(&file).unwrap() == "..." { break; }
+            std::thread::sleep(Duration::from_millis(200));
+        }
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "...");
+
+        // rate limits don't prevent fresh results from being used, even if the probe fails
+        let rate_file = dir.path("rate_file");
+        let args = ["--max-rate=10/1h", "--ttl=1h", "--stale=30m", "--validate-with=false", "--", "bash", "-c", COUNT_INVOCATIONS,
+            "arg0", rate_file.to_str().unwrap()];
+        for _ in 0..3 {
+            assert_eq!(succeed(bkt(dir.path("cache")).args(args)), "1");
+        }
+    }
+
+    #[test]
+    fn git_state() {
+        let dir = TestDir::temp().create("repo", FileType::Dir);
+        let repo = dir.path("repo");
+        let git = |args: &[&str]| {
+            let status = Command::new("git").arg("-C").arg(&repo)
+                .args(["-c", "user.name=bkt", "-c", "user.email=bkt@example.com"]).args(args)
+                .stdout(Stdio::null()).stderr(Stdio::null()).status().unwrap();
+            assert!(status.success(), "git {:?} failed", args);
+        };
+        git(&["init", "-q"]);
+        git(&["commit", "-q", "--allow-empty", "-m", "first"]);
+        let file = dir.path("file");
+        let args = ["--git-state", "--", "bash", "-c", COUNT_INVOCATIONS, "arg0", file.to_str().unwrap()];
+        let dirty_args = join(&["--git-dirty"], &args[1..]);
+
+        assert_eq!(succeed(bkt(dir.path("cache")).args(args).current_dir(&repo)), "1");
+        assert_eq!(succeed(bkt(dir.path("cache")).args(args).current_dir(&repo)), "1");
+        git(&["commit", "-q", "--allow-empty", "-m", "second"]);
+        assert_eq!(succeed(bkt(dir.path("cache")).args(args).current_dir(&repo)), "2");
+
+        assert_eq!(succeed(bkt(dir.path("cache")).args(&dirty_args).current_dir(&repo)), "3");
+        std::fs::write(repo.join("untracked"), "").unwrap();
+        assert_eq!(succeed(bkt(dir.path("cache")).args(&dirty_args).current_dir(&repo)), "4");
+        assert_eq!(succeed(bkt(dir.path("cache")).args(args).current_dir(&repo)), "2");
+
+        // checking for edits to tracked files doesn't itself change the key
+        std::fs::write(repo.join("tracked"), "a").unwrap();
+        git(&["add", "tracked"]);
+        git(&["commit", "-q", "-m", "third"]);
+        std::thread::sleep(Duration::from_millis(10));
+        std::fs::write(repo.join("tracked"), "b").unwrap();
+        assert_eq!(succeed(bkt(dir.path("cache")).args(&dirty_args).current_dir(&repo)), "5");
+        assert_eq!(succeed(bkt(dir.path("cache")).args(&dirty_args).current_dir(&repo)), "5");
+        assert_eq!(succeed(bkt(dir.path("cache")).args(&dirty_args).current_dir(&repo)), "5");
+
+        let output = run(bkt(dir.path("cache")).args(args).current_dir(dir.root()));
+        assert!(output.err.contains("not in a git repository"), "{}", output.err);
+    }
+
     #[test]
     fn force() {
         let dir = TestDir::temp();
@@ -429,6 +765,209 @@ mod cli {
         assert_eq!(output, "2");
     }
 
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn watch() {
+        let dir = TestDir::temp();
+        let (file, config) = (dir.path("file"), dir.path("config"));
+        std::fs::write(&config, "1").unwrap();
+        let watch_path = format!("--watch-path={}", config.display());
+        let args = [&watch_path, "--", "bash", "-c", COUNT_INVOCATIONS, "arg0", file.to_str().unwrap()];
+        assert_eq!(succeed(bkt(dir.path("cache")).args(args)), "1");
+        assert_eq!(succeed(bkt(dir.path("cache")).args(args)), "1");
+
+        let mut watcher = bkt(dir.path("cache")).arg("--watch").spawn().unwrap();
+        std::thread::sleep(Duration::from_millis(500));
+        std::fs::write(&config, "2").unwrap();
+        let mut result = String::new();
+        for _ in 0..20 {
+            std::thread::sleep(Duration::from_millis(200));
+            result = succeed(bkt(dir.path("cache")).args(args));
+            if result == "2" { break; }
+        }
+        assert_eq!(result, "2");
+
+        // refreshes run in the directory the command was registered from
+        let work = dir.path("work");
+        std::fs::create_dir(&work).unwrap();
+        let log = dir.path("log");
+        let args = [&watch_path, "--watch-refresh", "--", "bash", "-c", r#"pwd >> "${1:?}""#, "arg0", log.to_str().unwrap()];
+        succeed(bkt(dir.path("cache")).args(args).current_dir(&work));
+        std::thread::sleep(Duration::from_millis(500));
+        std::fs::write(&config, "3").unwrap();
+        for _ in 0..20 {
+            std::thread::sleep(Duration::from_millis(200));
+            if std::fs::read_to_string(&log).unwrap().lines().count() > 1 { break; }
+        }
+        watcher.kill().unwrap();
+        watcher.wait().unwrap();
+        let work = std::fs::canonicalize(&work).unwrap();
+        let work_line = format!("{}\n", work.display());
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), work_line.repeat(2));
+    }
+
+    #[test]
+    fn invalidate_tag() {
+        let dir = TestDir::temp();
+        let file = dir.path("file");
+        let args = ["--", "bash", "-c", COUNT_INVOCATIONS, "arg0", file.to_str().unwrap()];
+        let tagged_args = join(&["--tag=foo", "--tag=bar"], &args);
+        let tagged_scoped_args = join(&["--tag=foo", "--scope=baz"], &args);
+
+        assert_eq!(succeed(bkt(dir.path("cache")).args(&tagged_args)), "1");
+        assert_eq!(succeed(bkt(dir.path("cache")).args(&tagged_scoped_args)), "2");
+        assert_eq!(succeed(bkt(dir.path("cache")).args(&tagged_args)), "1");
+        assert_eq!(succeed(bkt(dir.path("cache")).args(&tagged_scoped_args)), "2");
+
+        assert_eq!(succeed(bkt(dir.path("cache")).arg("--invalidate-tag=other")), "");
+        assert_eq!(succeed(bkt(dir.path("cache")).args(args)), "1");
+
+        assert_eq!(succeed(bkt(dir.path("cache")).arg("--invalidate-tag=foo")), "");
+        assert_eq!(succeed(bkt(dir.path("cache")).args(&tagged_args)), "3");
+        assert_eq!(succeed(bkt(dir.path("cache")).args(&tagged_scoped_args)), "4");
+
+        for invalid in ["..", "../keys", "a/b", ""] {
+            let result = run(bkt(dir.path("cache")).arg(format!("--invalidate-tag={}", invalid)));
+            assert_eq!(result.status, Some(1), "{}", invalid);
+            let result = run(bkt(dir.path("cache")).arg(format!("--tag={}", invalid)).args(args));
+            assert_eq!(result.status, Some(1), "{}", invalid);
+        }
+        assert_eq!(succeed(bkt(dir.path("cache")).args(&tagged_args)), "3");
+    }
+
+    #[test]
+    fn export_import() {
+        let dir = TestDir::temp();
+        let file = dir.path("file");
+        let archive = dir.path("archive");
+        let args = ["--", "bash", "-c", COUNT_INVOCATIONS, "arg0", file.to_str().unwrap()];
+        let scoped_args = join(&["--scope=foo"], &args);
+
+        assert_eq!(succeed(bkt(dir.path("cache")).args(args)), "1");
+        assert_eq!(succeed(bkt(dir.path("cache")).args(&scoped_args)), "2");
+        assert_eq!(succeed(bkt(dir.path("cache")).arg(format!("--export={}", archive.display()))), "");
+
+        // only the scoped entry is imported
+        let import = format!("--import={}", archive.display());
+        assert_eq!(succeed(bkt(dir.path("new-cache")).args([&import, "--scope=foo"])), "");
+        assert_eq!(succeed(bkt(dir.path("new-cache")).args(&scoped_args)), "2");
+        assert_eq!(succeed(bkt(dir.path("new-cache")).args(args)), "3");
+
+        assert_eq!(succeed(bkt(dir.path("newer-cache")).args([&import, "--rebase-timestamps"])), "");
+        assert_eq!(succeed(bkt(dir.path("newer-cache")).args(&scoped_args)), "2");
+        assert_eq!(succeed(bkt(dir.path("newer-cache")).args(args)), "1");
+    }
+
+    #[test]
+    fn read_only() {
+        let dir = TestDir::temp();
+        let file = dir.path("file");
+        let args = ["--", "bash", "-c", COUNT_INVOCATIONS, "arg0", file.to_str().unwrap()];
+        let ro_args = join(&["--read-only"], &args);
+
+        // misses aren't cached, and the cache directory isn't created
+        assert_eq!(succeed(bkt(dir.path("cache")).args(&ro_args)), "1");
+        assert_eq!(succeed(bkt(dir.path("cache")).args(&ro_args)), "2");
+        assert!(!dir.path("cache").exists());
+
+        assert_eq!(succeed(bkt(dir.path("cache")).args(args)), "3");
+        assert_eq!(succeed(bkt(dir.path("cache")).args(&ro_args)), "3");
+
+        // expired data is left in place, so a longer TTL can still use it
+        make_dir_stale(dir.path("cache"), Duration::from_secs(120)).unwrap();
+        assert_eq!(succeed(bkt(dir.path("cache")).args(&ro_args)), "4");
+        assert_eq!(succeed(bkt(dir.path("cache")).args(join(&["--ttl=1h"], &args))), "3");
+    }
+
+    #[test]
+    fn shared_cache_dir() {
+        let dir = TestDir::temp();
+        let file = dir.path("file");
+        let args = ["--", "bash", "-c", COUNT_INVOCATIONS, "arg0", file.to_str().unwrap()];
+        let shared = format!("--shared-cache-dir={}", dir.path("shared").display());
+        let shared_args = join(&[shared.as_str(), "--max-ttl=100s"], &args);
+
+        assert_eq!(succeed(bkt(dir.path("a")).args(&shared_args)), "1");
+        assert_eq!(succeed(bkt(dir.path("b")).args(&shared_args)), "1");
+        // promoted into the local tier
+        assert_eq!(succeed(bkt(dir.path("b")).args(args)), "1");
+
+        // the local tier's results expire first, but the shared tier can still be used
+        make_dir_stale(dir.path("b"), Duration::from_secs(120)).unwrap();
+        make_dir_stale(dir.path("shared"), Duration::from_secs(120)).unwrap();
+        assert_eq!(succeed(bkt(dir.path("b")).args(join(&["--ttl=1h"], &shared_args))), "1");
+        assert_eq!(succeed(bkt(dir.path("b")).args(join(&["--ttl=1h", "--shared-max-ttl=100s"], &shared_args))), "2");
+    }
+
+    #[test]
+    fn gc() {
+        let dir = TestDir::temp();
+        let args = ["--ttl=5s", "--", "bash", "-c", "echo foo"];
+        succeed(bkt(dir.path("cache")).args(args));
+        make_dir_stale(dir.path("cache"), Duration::from_secs(10)).unwrap();
+
+        let out = succeed(bkt(dir.path("cache")).arg("--gc"));
+        assert!(out.starts_with("Removed 2 files ("), "{}", out);
+        assert!(!out.contains("(0 bytes)"), "{}", out);
+        // not throttled by the previous cleanup
+        assert_eq!(succeed(bkt(dir.path("cache")).arg("--gc")), "Removed 0 files (0 bytes)\n");
+    }
+
+    #[test]
+    fn stats() {
+        let dir = TestDir::temp();
+        let args = ["--", "bash", "-c", "sleep .1"];
+        succeed(bkt(dir.path("cache")).args(args));
+        succeed(bkt(dir.path("cache")).args(args));
+        succeed(bkt(dir.path("cache")).args(args));
+        run(bkt(dir.path("cache")).args(["--", "bash", "-c", "false"]));
+
+        let out = succeed(bkt(dir.path("cache")).arg("--stats"));
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines.len(), 3, "{}", out);
+        assert!(lines[0].ends_with("COMMAND"));
+        // sorted by savings
+        let fields: Vec<_> = lines[1].split_whitespace().collect();
+        assert_eq!(&fields[1..], ["2", "1", "0", "0", "bash", "-c", "sleep", ".1"]);
+        let fields: Vec<_> = lines[2].split_whitespace().collect();
+        assert_eq!(&fields[1..], ["0", "1", "0", "1", "bash", "-c", "false"]);
+
+        let scoped = succeed(bkt(dir.path("cache")).args(["--stats", "--scope=foo"]));
+        assert_eq!(scoped.lines().count(), 1);
+    }
+
+    #[test]
+    fn benchmark() {
+        let dir = TestDir::temp();
+        let file = dir.path("file");
+        let args = ["--benchmark=2", "--max-rate=10/1h", "--", "bash", "-c", COUNT_INVOCATIONS, "arg0",
+            file.to_str().unwrap()];
+
+        let out = succeed(bkt(dir.path("cache")).args(args));
+        assert!(out.contains("Results over 2 iteration(s)"), "{}", out);
+        for label in ["Original:", "Cache Miss:", "Cache Hit:"] {
+            assert!(out.contains(label), "{}", out);
+        }
+        // Each iteration runs the command directly and on a cache miss, but not on a cache hit
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "....");
+
+        // the benchmark's results, stats, and rate limits aren't left in the cache
+        let cache_dir = dir.path("cache").join(format!("bkt-{}.{}-cache",
+            env!("CARGO_PKG_VERSION_MAJOR"), env!("CARGO_PKG_VERSION_MINOR")));
+        for subdir in ["keys", "stats", "rates"] {
+            assert_eq!(std::fs::read_dir(cache_dir.join(subdir)).unwrap().count(), 0, "{}", subdir);
+        }
+
+        let err = run(bkt(dir.path("cache")).args(["--benchmark=0", "--", "true"]));
+        assert_eq!(err.status, Some(1));
+
+        // the command's own arguments are passed through unchanged
+        let out_file = dir.path("out");
+        succeed(bkt(dir.path("cache")).args(["--benchmark=1", "--", "bash", "-c", r#"printf '%s' "$1" > "$2""#,
+                                             "arg0", "--benchmark=x", out_file.to_str().unwrap()]));
+        assert_eq!(std::fs::read_to_string(&out_file).unwrap(), "--benchmark=x");
+    }
+
     #[test]
     fn concurrent_call_race() {
         let dir = TestDir::temp();